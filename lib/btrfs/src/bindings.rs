@@ -1,23 +1,253 @@
 use std::mem;
 use std::rt::heap;
-use std::{raw, ptr};
+use std::{iter, raw, ptr, u32, u64};
 use libc::c_int;
 use std::old_io::IoResult;
 use ioctl;
 
 const BTRFS_IOCTL_MAGIC: i32 = 0x94;
+const FIDEDUPERANGE_MAGIC: i32 = 0x94;
 
+// The kernel refuses extent-same arguments larger than a page
+const PAGE_SIZE: usize = 4096;
+
+// FIDEDUPERANGE (Linux 4.5+) works on any filesystem with shared extents, such as
+// XFS made with reflink=1. It started out as btrfs' BTRFS_IOC_FILE_EXTENT_SAME
+// (Linux 3.12+), and kept its number and argument layout.
 #[inline]
-pub unsafe fn btrfs_extent_same(fd: c_int, same: &mut btrfs_ioctl_same_args) -> IoResult<isize> {
-    let btrfs_ioc_file_extent_same = ioctl::iowr(
-        BTRFS_IOCTL_MAGIC,
+pub unsafe fn fideduperange(fd: c_int, same: &mut btrfs_ioctl_same_args) -> IoResult<isize> {
+    let fideduperange = ioctl::iowr(
+        FIDEDUPERANGE_MAGIC,
         54,
         ExtentSame::args_size()
     );
 
-    ioctl!(fd as c_int, btrfs_ioc_file_extent_same as c_int, same)
+    ioctl!(fd as c_int, fideduperange as c_int, same)
+}
+
+#[inline]
+pub unsafe fn btrfs_ino_lookup(fd: c_int, args: &mut btrfs_ioctl_ino_lookup_args) -> IoResult<isize> {
+    let btrfs_ioc_ino_lookup = ioctl::iowr(
+        BTRFS_IOCTL_MAGIC,
+        18,
+        mem::size_of::<btrfs_ioctl_ino_lookup_args>()
+    );
+
+    ioctl!(fd as c_int, btrfs_ioc_ino_lookup as c_int, args)
+}
+
+// FS_IOC_GETVERSION. The kernel writes an int, whatever the size in the number says.
+#[inline]
+pub unsafe fn fs_getversion(fd: c_int, generation: &mut u32) -> IoResult<isize> {
+    let fs_ioc_getversion = ioctl::ior(
+        'v' as i32,
+        1,
+        mem::size_of::<u64>()
+    );
+
+    ioctl!(fd as c_int, fs_ioc_getversion as c_int, generation)
 }
 
+pub const BTRFS_INO_LOOKUP_PATH_MAX: usize = 4080;
+
+#[repr(C)]
+pub struct btrfs_ioctl_ino_lookup_args {
+    pub treeid:   u64,                              /* in/out - subvolume to search (0 = the fd's own) */
+    pub objectid: u64,                              /* in - inode to resolve */
+    pub name:     [u8; BTRFS_INO_LOOKUP_PATH_MAX],  /* out - path relative to the subvolume */
+}
+
+#[inline]
+pub unsafe fn btrfs_fs_info(fd: c_int, args: &mut btrfs_ioctl_fs_info_args) -> IoResult<isize> {
+    let btrfs_ioc_fs_info = ioctl::ior(
+        BTRFS_IOCTL_MAGIC,
+        31,
+        mem::size_of::<btrfs_ioctl_fs_info_args>()
+    );
+
+    ioctl!(fd as c_int, btrfs_ioc_fs_info as c_int, args)
+}
+
+/* request csum_type and csum_size (Linux 5.5+) */
+pub const BTRFS_FS_INFO_FLAG_CSUM_INFO: u64 = 1 << 0;
+
+#[repr(C)]
+pub struct btrfs_ioctl_fs_info_args {
+    pub max_id:          u64,        /* out */
+    pub num_devices:     u64,        /* out */
+    pub fsid:            [u8; 16],   /* out */
+    pub nodesize:        u32,        /* out */
+    pub sectorsize:      u32,        /* out */
+    pub clone_alignment: u32,        /* out */
+    pub csum_type:       u16,        /* out */
+    pub csum_size:       u16,        /* out */
+    pub flags:           u64,        /* in/out */
+    pub generation:      u64,        /* out */
+    pub metadata_uuid:   [u8; 16],   /* out */
+    pub reserved:        [u8; 944],  /* pad to 1k */
+}
+
+// TREE_SEARCH_V2 (Linux 3.14+) takes a buffer of any size, unlike the original
+// TREE_SEARCH, whose 4KiB can't hold items as big as a leaf
+#[inline]
+pub unsafe fn btrfs_tree_search_v2(fd: c_int, search: &mut TreeSearch) -> IoResult<isize> {
+    let btrfs_ioc_tree_search_v2 = ioctl::iowr(
+        BTRFS_IOCTL_MAGIC,
+        17,
+        mem::size_of::<btrfs_ioctl_search_args_v2>()
+    );
+
+    ioctl!(fd as c_int, btrfs_ioc_tree_search_v2 as c_int, search.args())
+}
+
+#[repr(C)]
+#[derive(Copy)]
+pub struct btrfs_ioctl_search_key {
+    pub tree_id:      u64,  /* which root are we searching. 0 is the tree of tree roots */
+
+    /* keys returned will be >= min and <= max */
+    pub min_objectid: u64,
+    pub max_objectid: u64,
+    pub min_offset:   u64,
+    pub max_offset:   u64,
+
+    /* max and min transids to search for */
+    pub min_transid:  u64,
+    pub max_transid:  u64,
+
+    /* keys returned will be >= min and <= max */
+    pub min_type:     u32,
+    pub max_type:     u32,
+
+    /* how many items did userland ask for, and how many are we returning */
+    pub nr_items:     u32,
+
+    _unused:          u32,
+    _unused1:         u64,
+    _unused2:         u64,
+    _unused3:         u64,
+    _unused4:         u64,
+}
+
+#[repr(C)]
+#[derive(Copy)]
+pub struct btrfs_ioctl_search_header {
+    pub transid:  u64,
+    pub objectid: u64,
+    pub offset:   u64,
+    pub item_type: u32,
+    pub len:      u32,
+}
+
+#[repr(C)]
+pub struct btrfs_ioctl_search_args_v2 {
+    pub key:      btrfs_ioctl_search_key,
+    pub buf_size: u64,        /* in - size of buffer; out - on EOVERFLOW, the size needed */
+    buf:          [u64; 0],
+}
+
+// Search arguments along with their buffer
+pub struct TreeSearch {
+    allocation: Vec<u64>,
+}
+
+impl btrfs_ioctl_search_key {
+    pub fn new(tree_id: u64) -> btrfs_ioctl_search_key {
+        btrfs_ioctl_search_key {
+            tree_id:      tree_id,
+            min_objectid: 0,
+            max_objectid: u64::MAX,
+            min_offset:   0,
+            max_offset:   u64::MAX,
+            min_transid:  0,
+            max_transid:  u64::MAX,
+            min_type:     0,
+            max_type:     u32::MAX,
+            nr_items:     0,
+            _unused:      0,
+            _unused1:     0,
+            _unused2:     0,
+            _unused3:     0,
+            _unused4:     0,
+        }
+    }
+}
+
+impl TreeSearch {
+    pub fn new(key: btrfs_ioctl_search_key, buf_size: usize) -> TreeSearch {
+        let args_size = mem::size_of::<btrfs_ioctl_search_args_v2>();
+        let words     = (args_size + buf_size + 7) / 8;
+
+        let mut search = TreeSearch { allocation: iter::repeat(0u64).take(words).collect() };
+
+        search.args().key      = key;
+        search.args().buf_size = buf_size as u64;
+
+        search
+    }
+
+    pub fn args(&mut self) -> &mut btrfs_ioctl_search_args_v2 {
+        unsafe { mem::transmute(self.allocation.as_mut_ptr()) }
+    }
+
+    pub fn key(&self) -> &btrfs_ioctl_search_key {
+        unsafe { &(*(self.allocation.as_ptr() as *const btrfs_ioctl_search_args_v2)).key }
+    }
+
+    pub fn buf_size(&self) -> usize {
+        self.allocation.len() * 8 - mem::size_of::<btrfs_ioctl_search_args_v2>()
+    }
+
+    fn buf(&self) -> &[u8] {
+        unsafe {
+            let args_size = mem::size_of::<btrfs_ioctl_search_args_v2>();
+            let buf_ptr   = (self.allocation.as_ptr() as *const u8).offset(args_size as isize);
+
+            mem::transmute(raw::Slice { data: buf_ptr, len: self.buf_size() })
+        }
+    }
+
+    // The items returned by the last search, as (header, item data) pairs
+    pub fn items(&self) -> Vec<(btrfs_ioctl_search_header, &[u8])> {
+        let buf         = self.buf();
+        let nr_items    = self.key().nr_items;
+        let header_size = mem::size_of::<btrfs_ioctl_search_header>();
+        let mut items   = Vec::with_capacity(nr_items as usize);
+        let mut offset  = 0us;
+
+        for _ in (0..nr_items) {
+            if offset + header_size > buf.len() { break; }
+
+            // Item data is only padded to 4 bytes, so the headers after the first
+            // may be misaligned, and have to be copied out instead of read in place
+            let header: btrfs_ioctl_search_header = unsafe {
+                let mut header: btrfs_ioctl_search_header = mem::zeroed();
+
+                ptr::copy_nonoverlapping(
+                    &mut header as *mut btrfs_ioctl_search_header as *mut u8,
+                    buf[offset..].as_ptr(),
+                    header_size
+                );
+
+                header
+            };
+
+            let data_start = offset + header_size;
+            let data_end   = data_start + header.len as usize;
+
+            if data_end > buf.len() { break; }
+
+            items.push((header, &buf[data_start..data_end]));
+            offset = data_end;
+        }
+
+        items
+    }
+}
+
+// Status of a destination whose contents don't match the source's
+pub const BTRFS_SAME_DATA_DIFFERS: i32 = 1;
+
 #[repr(C)]
 pub struct btrfs_ioctl_same_args {
     pub logical_offset: u64,  /* in - start of extent in source */
@@ -49,6 +279,8 @@ pub struct ExtentSame {
 
 impl ExtentSame {
     pub fn new(info_count: usize) -> ExtentSame {
+        assert!(info_count <= ExtentSame::max_infos(), "Too many destinations for a single call");
+
         let args_size  = ExtentSame::args_size();
         let infos_size = ExtentSame::infos_size(info_count);
 
@@ -94,6 +326,11 @@ impl ExtentSame {
         }
     }
 
+    // How many destinations fit on a single call
+    pub fn max_infos() -> usize {
+        (PAGE_SIZE - ExtentSame::args_size()) / mem::size_of::<btrfs_ioctl_same_extent_info>()
+    }
+
     fn allocation_size(&mut self) -> usize {
         ExtentSame::args_size() +
             ExtentSame::infos_size(self.args().dest_count as usize)
@@ -0,0 +1,220 @@
+use std::old_io::{File, IoResult};
+use std::os::unix::prelude::*;
+use std::{cmp, mem, u32, u64};
+
+use fiemap::bindings::{FiemapRequest, ExtentFlags, extent_flags};
+use bindings;
+
+const BTRFS_CSUM_TREE_OBJECTID:    u64 = 7;
+const BTRFS_EXTENT_CSUM_OBJECTID:  u64 = -10i64 as u64;
+const BTRFS_EXTENT_CSUM_KEY:       u32 = 128;
+
+// Used when the kernel is too old to tell us. Before 5.5, crc32c was the only checksum
+// option, and before 4.3 fs_info didn't report node or sector sizes.
+const DEFAULT_CSUM_SIZE:  usize = 4;
+const DEFAULT_SECTORSIZE: u64   = 4096;
+const DEFAULT_NODESIZE:   u64   = 16384;
+
+// How many leaves' worth of items each tree search asks for
+const SEARCH_BUFFER_LEAVES: usize = 4;
+
+#[derive(Copy, Show)]
+pub struct FsInfo {
+    pub fsid:       [u8; 16],
+    pub sectorsize: u64,
+    pub nodesize:   u64,
+    pub csum_size:  usize,
+}
+
+pub fn fs_info(file: &File) -> IoResult<FsInfo> {
+    let mut args: bindings::btrfs_ioctl_fs_info_args = unsafe { mem::zeroed() };
+    args.flags = bindings::BTRFS_FS_INFO_FLAG_CSUM_INFO;
+
+    unsafe {
+        try!(bindings::btrfs_fs_info(file.as_raw_fd(), &mut args));
+    }
+
+    let csum_size = if args.flags & bindings::BTRFS_FS_INFO_FLAG_CSUM_INFO != 0 {
+        args.csum_size as usize
+    } else {
+        DEFAULT_CSUM_SIZE
+    };
+
+    Ok(FsInfo {
+        fsid:       args.fsid,
+        sectorsize: if args.sectorsize > 0 { args.sectorsize as u64 } else { DEFAULT_SECTORSIZE },
+        nodesize:   if args.nodesize > 0   { args.nodesize as u64 }   else { DEFAULT_NODESIZE },
+        csum_size:  csum_size,
+    })
+}
+
+// The stored checksums for every data sector of the file, in file order. Two files
+// with different checksums have different contents; equal checksums still need a
+// full comparison.
+//
+// Returns `None` when the checksums can't stand for the file contents: holes,
+// inline, compressed or preallocated extents, and files without checksums (nodatasum).
+// Searching the csum tree requires CAP_SYS_ADMIN.
+pub fn data_csums(file: &File) -> IoResult<Option<Vec<u8>>> {
+    let info = try!(fs_info(file));
+    let size = try!(file.stat()).size;
+
+    let unusable: ExtentFlags = extent_flags::UNKNOWN | extent_flags::DELALLOC |
+        extent_flags::ENCODED | extent_flags::DATA_ENCRYPTED | extent_flags::NOT_ALIGNED |
+        extent_flags::DATA_INLINE | extent_flags::UNWRITTEN;
+
+    let mut request = try!(FiemapRequest::new(file.as_raw_fd()));
+
+    let mut csums    = Vec::new();
+    let mut expected = 0u64;
+
+    for extent in request.extents().iter() {
+        if extent.flags().intersects(unusable) || extent.logical() != expected {
+            return Ok(None);
+        }
+
+        match try!(csums_for_range(file, &info, extent.physical(), extent.length())) {
+            Some(extent_csums) => csums.push_all(&extent_csums[]),
+            None               => return Ok(None),
+        }
+
+        expected += extent.length();
+    }
+
+    if expected < size {
+        return Ok(None);
+    }
+
+    Ok(Some(csums))
+}
+
+// Checksums for the sectors on [start, start + length), given as btrfs logical
+// addresses. `None` if any of the sectors has no checksum.
+fn csums_for_range(file: &File, info: &FsInfo, start: u64, length: u64) -> IoResult<Option<Vec<u8>>> {
+    let end = start + length;
+    let csum_size = info.csum_size as u64;
+
+    // A csum item can't be bigger than a leaf, so the one covering `start` can't
+    // begin earlier than this
+    let max_item_span = info.nodesize / csum_size * info.sectorsize;
+
+    let mut key = bindings::btrfs_ioctl_search_key::new(BTRFS_CSUM_TREE_OBJECTID);
+
+    key.min_objectid = BTRFS_EXTENT_CSUM_OBJECTID;
+    key.max_objectid = BTRFS_EXTENT_CSUM_OBJECTID;
+    key.min_type     = BTRFS_EXTENT_CSUM_KEY;
+    key.max_type     = BTRFS_EXTENT_CSUM_KEY;
+    key.min_offset   = start - cmp::min(start, max_item_span);
+    key.max_offset   = end - 1;
+
+    // Room for a few leaves' worth of items. A single item can take up almost a
+    // whole leaf.
+    let mut buf_size = SEARCH_BUFFER_LEAVES * info.nodesize as usize;
+
+    let mut csums = Vec::with_capacity((length / info.sectorsize * csum_size) as usize);
+    let mut next  = start;
+
+    while next < end {
+        key.nr_items = u32::MAX;
+        let mut search = bindings::TreeSearch::new(key, buf_size);
+
+        let result = unsafe { bindings::btrfs_tree_search_v2(file.as_raw_fd(), &mut search) };
+
+        if let Err(err) = result {
+            // EOVERFLOW: the next item didn't fit, and the kernel tells how much would
+            let needed = search.args().buf_size as usize;
+            if needed <= buf_size { return Err(err); }
+
+            buf_size = needed;
+            continue;
+        }
+
+        let items = search.items();
+        if items.is_empty() { break; }
+
+        // Same as above, for kernels that hand back the item empty instead
+        if items[0].0.len == 0 {
+            buf_size *= 2;
+            continue;
+        }
+
+        for &(ref header, data) in items.iter() {
+            let item_start = header.offset;
+            let item_end   = item_start + (data.len() as u64 / csum_size) * info.sectorsize;
+
+            if item_end <= next { continue; }
+
+            // There's a gap between what we have and this item
+            if item_start > next { return Ok(None); }
+
+            let last = cmp::min(item_end, end);
+            let from = ((next - item_start) / info.sectorsize * csum_size) as usize;
+            let to   = ((last - item_start) / info.sectorsize * csum_size) as usize;
+
+            csums.push_all(&data[from..to]);
+            next = last;
+
+            if next >= end { break; }
+        }
+
+        let last_offset = items[items.len() - 1].0.offset;
+        if last_offset == u64::MAX { break; }
+
+        key.min_offset = last_offset + 1;
+    }
+
+    if next < end {
+        Ok(None)
+    } else {
+        Ok(Some(csums))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{data_csums, fs_info};
+    use std::old_io::File;
+    use std::old_io::fs;
+    use std::os;
+
+    // Big enough that its checksums take many items, most of them whole leaves,
+    // and several searches to get through
+    const FILE_SIZE: usize = 384 * 1024 * 1024;
+
+    // Needs a btrfs directory (DEDUP_TEST_DIR, as in the dedup tests), and root
+    #[test]
+    fn test_csums_of_a_large_file() {
+        let dir = match os::getenv("DEDUP_TEST_DIR") {
+            Some(dir) => Path::new(dir),
+            None      => return,
+        };
+
+        let path = dir.join("csums-large");
+        let chunk: Vec<u8> = (0..1024 * 1024us).map(|i| (i % 251) as u8).collect();
+
+        {
+            let mut file = File::create(&path).unwrap();
+
+            for _ in (0..FILE_SIZE / chunk.len()) {
+                file.write_all(&chunk[]).unwrap();
+            }
+
+            // Checksums are only there once the data is on disk
+            file.fsync().unwrap();
+        }
+
+        let file   = File::open(&path).unwrap();
+        let info   = fs_info(&file);
+        let result = data_csums(&file);
+
+        fs::unlink(&path).unwrap();
+
+        let info = match info {
+            Ok(info) => info,
+            Err(..)  => return,   // Not btrfs
+        };
+
+        let csums = result.unwrap().expect("The file should have checksums");
+        assert_eq!(csums.len(), FILE_SIZE / info.sectorsize as usize * info.csum_size);
+    }
+}
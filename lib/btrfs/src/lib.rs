@@ -1,18 +1,98 @@
-#![feature(libc, io, collections, alloc)]
+#![feature(libc, io, collections, alloc, std_misc)]
 
 extern crate libc;
+extern crate fiemap;
+extern crate time;
 
 #[macro_use]
 extern crate ioctl;
 #[macro_use]
 extern crate log;
 
-use std::old_io::{File, FileMode, FileAccess};
+use libc::{c_char, c_int};
+
+use std::ffi::CString;
+use std::old_io::{self, File, FileMode, FileAccess, IoError, IoResult};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use std::os::unix::prelude::*;
+use std::time::Duration;
+use std::{iter, mem};
 
 #[allow(non_camel_case_types)]
 mod bindings;
+mod csum;
+
+pub use csum::{FsInfo, fs_info, data_csums};
+
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+const BTRFS_SUPER_MAGIC: i64 = 0x9123683e;
+const XFS_SUPER_MAGIC:   i64 = 0x58465342;
+
+// Only f_type matters here. The rest is padding, sized for 64-bit glibc.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct statfs {
+    f_type: i64,
+    rest:   [u64; 15],
+}
+
+extern "C" {
+    fn statfs(path: *const c_char, buf: *mut statfs) -> c_int;
+}
+
+// Filesystems that can share extents between files, and so dedup them
+#[derive(Show, PartialEq, Copy)]
+pub enum Filesystem {
+    Btrfs,
+
+    // Only when made with reflink=1, which can't be told from here. Without it,
+    // every dedup fails.
+    Xfs,
+}
+
+// Tells the filesystem holding `path` from its magic number. `None` if it's one
+// that can't dedup.
+pub fn filesystem(path: &Path) -> IoResult<Option<Filesystem>> {
+    let c_path = CString::from_slice(path.as_vec());
+    let mut buf: statfs = unsafe { mem::zeroed() };
+
+    if unsafe { statfs(c_path.as_ptr(), &mut buf) } < 0 {
+        return Err(IoError::last_error());
+    }
+
+    Ok(match buf.f_type {
+        BTRFS_SUPER_MAGIC => Some(Filesystem::Btrfs),
+        XFS_SUPER_MAGIC   => Some(Filesystem::Xfs),
+        _                 => None,
+    })
+}
+
+pub fn subvolume_id(file: &File) -> IoResult<u64> {
+    let mut args = bindings::btrfs_ioctl_ino_lookup_args {
+        treeid:   0,
+        objectid: BTRFS_FIRST_FREE_OBJECTID,
+        name:     [0u8; bindings::BTRFS_INO_LOOKUP_PATH_MAX],
+    };
+
+    unsafe {
+        try!(bindings::btrfs_ino_lookup(file.as_raw_fd(), &mut args));
+    }
+
+    Ok(args.treeid)
+}
+
+// The inode generation, which changes when an inode number is reused by a new file
+pub fn generation(file: &File) -> IoResult<u64> {
+    let mut generation = 0u32;
+
+    unsafe {
+        try!(bindings::fs_getversion(file.as_raw_fd(), &mut generation));
+    }
+
+    Ok(generation as u64)
+}
 
 pub struct Dedup<'a> {
     source: Arc<Path>,
@@ -26,71 +106,584 @@ pub fn new_dedup<'a>(source: Arc<Path>, destinations: &'a [Arc<Path>]) -> Dedup<
     }
 }
 
+// Why the source of a dedup couldn't be used. Nothing was deduped.
+#[derive(Show)]
+pub enum SourceError {
+    Open(IoError),
+    Stat(IoError),
+}
+
+pub type DedupResult = Result<DedupReport, SourceError>;
+
+pub struct DedupReport {
+    pub source: Arc<Path>,
+    pub destinations: Vec<DestinationReport>,
+
+    // Extent-same ioctls issued
+    pub calls: usize,
+    pub elapsed: Duration,
+}
+
+pub struct DestinationReport {
+    pub path: Arc<Path>,
+    pub bytes_deduped: u64,
+
+    // Why it wasn't deduped in full, if it wasn't
+    pub status: Result<(), DestinationError>,
+
+    // Why the partial block at the end of the file wasn't deduped, if it was tried
+    // and failed. The rest of the file is unaffected.
+    pub tail_error: Option<DestinationError>,
+}
+
+impl DedupReport {
+    pub fn bytes_deduped(&self) -> u64 {
+        self.destinations.iter().fold(0, |total, destination| total + destination.bytes_deduped)
+    }
+
+    fn new(source: Arc<Path>, destinations: Vec<Destination>, calls: usize, started: u64) -> DedupReport {
+        let destinations = destinations.into_iter().map(|destination| {
+            DestinationReport {
+                path: destination.path,
+                bytes_deduped: destination.bytes_deduped,
+                status: match destination.error {
+                    Some(err) => Err(err),
+                    None      => Ok(()),
+                },
+                tail_error: destination.tail_error,
+            }
+        }).collect();
+
+        DedupReport {
+            source: source,
+            destinations: destinations,
+            calls: calls,
+            elapsed: Duration::nanoseconds((time::precise_time_ns() - started) as i64),
+        }
+    }
+}
+
 impl<'a> Dedup<'a> {
-    pub fn perform(self) -> usize {
-        let source_file = {
-            match File::open(&*self.source) {
-                Ok(file) => file,
-                Err(..)  => panic!("Couldn't open file {} for reading", self.source.display()),
+    pub fn perform(self) -> DedupResult {
+        let started = time::precise_time_ns();
+
+        let source_file = try!(File::open(&*self.source).map_err(SourceError::Open));
+        let file_size   = try!(source_file.stat().map_err(SourceError::Stat)).size;
+
+        let mut destinations = Vec::with_capacity(self.destinations.len());
+        let mut calls = 0;
+
+        if file_size < 4096 {
+            // Not even a single block, so nothing can be shared
+            destinations.extend(self.destinations.iter().map(|dest_path| {
+                Destination {
+                    path: dest_path.clone(),
+                    fd: -1,
+                    logical_offset: 0,
+                    bytes_deduped: 0,
+                    error: Some(DestinationError::TooSmall),
+                    tail_error: None,
+                }
+            }));
+        } else {
+            // Each call can only take so many destinations
+            for batch in self.destinations.chunks(bindings::ExtentSame::max_infos()) {
+                let (_files, mut batch_destinations) = open_destinations(batch.iter().map(|dest_path| {
+                    (dest_path, 0)
+                }));
+
+                let aligned_size = file_size - (file_size % 4096);
+                calls += extent_same(&source_file, 0, aligned_size, &mut batch_destinations[]);
+
+                if aligned_size < file_size {
+                    let tail_size = file_size - aligned_size;
+                    calls += dedup_tail(&source_file, aligned_size, tail_size, &mut batch_destinations[]);
+                }
+
+                destinations.extend(batch_destinations.into_iter());
             }
+        }
+
+        Ok(DedupReport::new(self.source, destinations, calls, started))
+    }
+}
+
+pub struct RangeDedup {
+    source: Arc<Path>,
+    source_offset: u64,
+    length: u64,
+    destinations: Vec<(Arc<Path>, u64)>,
+}
+
+// Dedups `length` bytes starting at `source_offset` on the source into each
+// destination, starting on its paired offset. Offsets and length must be block-aligned.
+pub fn new_range_dedup(
+    source: Arc<Path>,
+    source_offset: u64,
+    length: u64,
+    destinations: Vec<(Arc<Path>, u64)>) -> RangeDedup
+{
+    RangeDedup {
+        source: source,
+        source_offset: source_offset,
+        length: length,
+        destinations: destinations,
+    }
+}
+
+impl RangeDedup {
+    pub fn perform(self) -> DedupResult {
+        let started = time::precise_time_ns();
+
+        let source_file = try!(File::open(&*self.source).map_err(SourceError::Open));
+
+        let paths = self.destinations.iter().map(|&(ref dest_path, offset)| (dest_path, offset));
+        let (_files, mut destinations) = open_destinations(paths);
+
+        let calls = if self.length > 0 {
+            extent_same(&source_file, self.source_offset, self.length, &mut destinations[])
+        } else {
+            0
         };
 
-        let dest_count = self.destinations.len();
-        let dest_files = self.destinations.iter().filter_map(|dest_path| {
-            File::open_mode(&**dest_path, FileMode::Open, FileAccess::ReadWrite).ok()
-        }).collect::<Vec<_>>();
+        Ok(DedupReport::new(self.source, destinations, calls, started))
+    }
+}
+
+// Opens each destination for writing, as the ioctl requires, starting at its paired
+// offset. The files have to be kept open while their destinations are in use. The
+// ones that can't be opened come out failed already.
+fn open_destinations<'a, I>(paths: I) -> (Vec<File>, Vec<Destination>)
+    where I: Iterator<Item = (&'a Arc<Path>, u64)>
+{
+    let mut files = Vec::new();
+    let mut destinations = Vec::new();
 
-        let file_size = match source_file.stat() {
-            Ok(stat) => stat.size,
-            Err(..)  => panic!("Couldn't get source file ({}) size", self.source.display()),
+    for (dest_path, offset) in paths {
+        let mut destination = Destination {
+            path: dest_path.clone(),
+            fd: -1,
+            logical_offset: offset,
+            bytes_deduped: 0,
+            error: None,
+            tail_error: None,
         };
 
-        if file_size < 4096 {
-            return 0;
+        match File::open_mode(&**dest_path, FileMode::Open, FileAccess::ReadWrite) {
+            Ok(file) => {
+                destination.fd = file.as_raw_fd() as i64;
+                files.push(file);
+            },
+
+            Err(err) => destination.error = Some(DestinationError::OpenFailed(err)),
         }
 
-        let mut same = bindings::ExtentSame::new(dest_count);
+        destinations.push(destination);
+    }
+
+    (files, destinations)
+}
+
+// Why a destination couldn't be (fully) deduped
+#[derive(Show, Clone, PartialEq)]
+pub enum DestinationError {
+    // It couldn't be opened for writing
+    OpenFailed(IoError),
+
+    // Its contents differ from the source's (BTRFS_SAME_DATA_DIFFERS)
+    DataDiffers,
+
+    // The kernel refused it, with this errno
+    Refused(i32),
+
+    // The whole call failed, and this destination along with it
+    CallFailed(IoError),
+
+    // The kernel stopped making progress before reaching the end of the range
+    Incomplete,
+
+    // The files are smaller than a block, and can't be deduped
+    TooSmall,
+}
+
+// Dedup progress for a single destination
+#[derive(Clone)]
+struct Destination {
+    path: Arc<Path>,
+    fd: i64,
+    logical_offset: u64,
+    bytes_deduped: u64,
+    error: Option<DestinationError>,
+    tail_error: Option<DestinationError>,
+}
+
+const TAIL_SUPPORTED:   usize = 1;
+const TAIL_UNSUPPORTED: usize = 2;
 
-        same.args().logical_offset = 0;
-        same.args().length = file_size - (file_size % 4096);
+// Whether the kernel takes a length that isn't block-aligned when it reaches the
+// end of the files. Zero until found out, on the first try.
+static TAIL_SUPPORT: AtomicUsize = ATOMIC_USIZE_INIT;
 
-        for (file, info) in dest_files.iter().zip(same.infos().iter_mut()) {
-            info.fd = file.as_raw_fd() as i64;
-            info.logical_offset = 0;
+// Dedups the partial block at the end of the files, on top of the aligned part
+// that `destinations` already went through, from `offset` on. Kernels that don't
+// allow it reject it with EINVAL, either for the whole call or for each destination,
+// which isn't held against the destinations. Returns the number of calls issued.
+fn dedup_tail(source_file: &File, offset: u64, length: u64, destinations: &mut [Destination]) -> usize {
+    if TAIL_SUPPORT.load(Ordering::SeqCst) == TAIL_UNSUPPORTED { return 0; }
+
+    let mut tails: Vec<Destination> = destinations.iter().map(|destination| {
+        Destination {
+            logical_offset: destination.logical_offset + offset,
+            bytes_deduped: 0,
+            .. destination.clone()
         }
+    }).collect();
 
-        let mut total_dedup = 0us;
+    let calls = extent_same(source_file, offset, length, &mut tails[]);
 
-        loop {
-            let errored = unsafe {
-                let result = bindings::btrfs_extent_same(source_file.as_raw_fd(), same.args());
+    let rejected = {
+        // Destinations that had already failed weren't part of the call
+        let tried: Vec<&Destination> = destinations.iter().zip(tails.iter())
+            .filter(|&(destination, _)| destination.error.is_none())
+            .map(|(_, tail)| tail)
+            .collect();
 
-                match result {
-                    Err(err) => { warn!("Error: {}", err); true },
-                    Ok(_)  => {
-                        same.infos().iter().any(|info| info.status != 0)
-                    }
-                }
-            };
+        !tried.is_empty() && tried.iter().all(|tail| rejects_partial_blocks(&tail.error))
+    };
+
+    if rejected {
+        if TAIL_SUPPORT.swap(TAIL_UNSUPPORTED, Ordering::SeqCst) != TAIL_UNSUPPORTED {
+            info!("The kernel can't dedup partial blocks at the end of files, leaving them alone");
+        }
 
-            if errored { break; }
+        return calls;
+    }
 
-            let offset = same.infos()[0].bytes_deduped;
-            assert!(same.infos().tail().iter().all(|info| info.bytes_deduped == offset));
+    TAIL_SUPPORT.store(TAIL_SUPPORTED, Ordering::SeqCst);
 
-            total_dedup += (offset as usize) * dest_count;
+    for (destination, tail) in destinations.iter_mut().zip(tails.into_iter()) {
+        // Failed destinations carried their error over to the tail
+        if destination.error.is_some() { continue; }
 
-            if same.args().length < offset || offset == 0 { break; }
+        destination.bytes_deduped += tail.bytes_deduped;
+        destination.tail_error = tail.error;
+    }
 
-            same.args().logical_offset += offset;
-            same.args().length -= offset;
-            if same.args().length < 1 { break; }
+    calls
+}
 
-            for info in same.infos().iter_mut() {
-                info.logical_offset += offset;
+// Whether `error` is how the kernel turns down a length that isn't block-aligned
+fn rejects_partial_blocks(error: &Option<DestinationError>) -> bool {
+    match *error {
+        Some(DestinationError::CallFailed(ref err)) => err.kind == old_io::InvalidInput,
+        Some(DestinationError::Refused(errno))      => errno == libc::EINVAL,
+        _ => false,
+    }
+}
+
+// Issues the extent-same ioctl until `length` bytes from `source_offset` are deduped
+// into every destination (the kernel may do it in smaller pieces, and not the same
+// for each). A destination that fails or stalls is dropped from the later calls,
+// without holding back the others. Returns the number of calls issued.
+fn extent_same(source_file: &File, source_offset: u64, length: u64, destinations: &mut [Destination]) -> usize {
+    // How far into the range each destination got
+    let mut done: Vec<u64> = iter::repeat(0).take(destinations.len()).collect();
+    let mut calls = 0us;
+
+    while let Some((offset, batch)) = next_batch(destinations, &done[], length) {
+        let mut same = bindings::ExtentSame::new(batch.len());
+
+        same.args().logical_offset = source_offset + offset;
+        same.args().length = length - offset;
+
+        for (&index, info) in batch.iter().zip(same.infos().iter_mut()) {
+            info.fd = destinations[index].fd;
+            info.logical_offset = destinations[index].logical_offset + offset;
+        }
+
+        calls += 1;
+
+        let result = unsafe { bindings::fideduperange(source_file.as_raw_fd(), same.args()) };
+
+        if let Err(err) = result {
+            for &index in batch.iter() {
+                destinations[index].error = Some(DestinationError::CallFailed(err.clone()));
             }
+
+            continue;
+        }
+
+        let outcomes: Vec<(i32, u64)> = same.infos().iter().map(|info| {
+            (info.status, info.bytes_deduped)
+        }).collect();
+
+        record_progress(destinations, &mut done[], &batch[], &outcomes[]);
+    }
+
+    calls
+}
+
+// The destinations for the next call, and how far into the range it starts: the
+// ones still going that got the least far. Those further along wait for the others
+// to catch up, and then go on together.
+fn next_batch(destinations: &[Destination], done: &[u64], length: u64) -> Option<(u64, Vec<usize>)> {
+    let active: Vec<usize> = (0..destinations.len())
+        .filter(|&index| destinations[index].error.is_none() && done[index] < length)
+        .collect();
+
+    let offset = match active.iter().map(|&index| done[index]).min() {
+        Some(offset) => offset,
+        None         => return None,
+    };
+
+    Some((offset, active.into_iter().filter(|&index| done[index] == offset).collect()))
+}
+
+// Records what a call did for each destination of `batch`, given as (status, bytes
+// deduped) pairs in the same order
+fn record_progress(destinations: &mut [Destination], done: &mut [u64], batch: &[usize], outcomes: &[(i32, u64)]) {
+    for (&index, &(status, bytes_deduped)) in batch.iter().zip(outcomes.iter()) {
+        let destination = &mut destinations[index];
+
+        match status {
+            // Nothing went wrong, but nothing was done either
+            0 if bytes_deduped == 0 => destination.error = Some(DestinationError::Incomplete),
+
+            0 => {
+                destination.bytes_deduped += bytes_deduped;
+                done[index] += bytes_deduped;
+            },
+
+            bindings::BTRFS_SAME_DATA_DIFFERS => destination.error = Some(DestinationError::DataDiffers),
+
+            status => destination.error = Some(DestinationError::Refused(-status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_dedup, next_batch, open_destinations, record_progress, rejects_partial_blocks};
+    use super::{Destination, DestinationError};
+    use std::old_io::{File, IoError, TempDir};
+    use std::old_io::fs;
+    use std::os;
+    use std::sync::Arc;
+
+    // A directory on a filesystem that can dedup, such as a loopback btrfs image,
+    // or XFS made with reflink=1. Tests that need one pass trivially without it.
+    fn scratch_dir() -> Option<Path> {
+        os::getenv("DEDUP_TEST_DIR").map(|dir| Path::new(dir))
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn create(dir: &Path, name: &str, content: &[u8]) -> Arc<Path> {
+        let path = Arc::new(dir.join(name));
+        File::create(&*path).write_all(content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_dedups_identical_files() {
+        let dir = match scratch_dir() {
+            Some(dir) => dir,
+            None      => return,
+        };
+
+        let content = content(256 * 1024);
+
+        let source      = create(&dir, "dedup-source", &content[]);
+        let destination = create(&dir, "dedup-destination", &content[]);
+
+        let destinations = [destination.clone()];
+        let report = new_dedup(source.clone(), &destinations).perform().unwrap();
+
+        fs::unlink(&*source).unwrap();
+        fs::unlink(&*destination).unwrap();
+
+        assert_eq!(report.bytes_deduped(), content.len() as u64);
+        assert_eq!(report.destinations[0].status, Ok(()));
+    }
+
+    #[test]
+    fn test_destinations_that_fail_to_open_arent_passed_on() {
+        let tempdir = TempDir::new("dedup").unwrap();
+        let missing = Arc::new(tempdir.path().join("missing"));
+
+        let (files, destinations) = open_destinations(vec![(&missing, 0)].into_iter());
+
+        assert!(files.is_empty());
+        assert_eq!(destinations[0].fd, -1);
+
+        match destinations[0].error {
+            Some(DestinationError::OpenFailed(_)) => (),
+            ref other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    // Runs anywhere: the missing destination fails to open, wherever that is, and
+    // the other one still gets its call (which fails without shared extents)
+    #[test]
+    fn test_open_failures_dont_stop_other_destinations() {
+        let tempdir = TempDir::new("dedup").unwrap();
+        let dir = scratch_dir().unwrap_or(tempdir.path().clone());
+
+        let content = content(64 * 1024);
+
+        let source      = create(&dir, "open-failure-source", &content[]);
+        let destination = create(&dir, "open-failure-destination", &content[]);
+        let missing     = Arc::new(dir.join("open-failure-missing"));
+
+        let destinations = [missing.clone(), destination.clone()];
+        let report = new_dedup(source.clone(), &destinations).perform().unwrap();
+
+        fs::unlink(&*source).unwrap();
+        fs::unlink(&*destination).unwrap();
+
+        match report.destinations[0].status {
+            Err(DestinationError::OpenFailed(_)) => (),
+            ref other => panic!("Unexpected status {:?}", other),
+        }
+
+        match report.destinations[1].status {
+            Err(DestinationError::OpenFailed(_)) => panic!("The destination should have opened"),
+            Ok(()) => assert_eq!(report.destinations[1].bytes_deduped, content.len() as u64),
+            Err(_) => assert!(scratch_dir().is_none()),
+        }
+    }
+
+    // Larger than what a single call dedups, so that the destination that differs
+    // is left out of the later calls
+    #[test]
+    fn test_differing_destinations_dont_hold_back_others() {
+        let dir = match scratch_dir() {
+            Some(dir) => dir,
+            None      => return,
+        };
+
+        let content = content(32 * 1024 * 1024);
+        let mut different = content.clone();
+        different[0] = different[0].wrapping_add(1);
+
+        let source      = create(&dir, "differs-source", &content[]);
+        let destination = create(&dir, "differs-destination", &content[]);
+        let differing   = create(&dir, "differs-differing", &different[]);
+
+        let destinations = [differing.clone(), destination.clone()];
+        let report = new_dedup(source.clone(), &destinations).perform().unwrap();
+
+        for path in [source, destination, differing].iter() {
+            fs::unlink(&**path).unwrap();
+        }
+
+        assert_eq!(report.destinations[0].status, Err(DestinationError::DataDiffers));
+        assert_eq!(report.destinations[0].bytes_deduped, 0);
+
+        assert_eq!(report.destinations[1].status, Ok(()));
+        assert_eq!(report.destinations[1].bytes_deduped, content.len() as u64);
+    }
+
+    // Whether or not the kernel takes the partial block, the aligned part is
+    // deduped and the destination isn't blamed for it
+    #[test]
+    fn test_partial_last_block() {
+        let dir = match scratch_dir() {
+            Some(dir) => dir,
+            None      => return,
+        };
+
+        let content = content(64 * 1024 + 100);
+
+        let source      = create(&dir, "tail-source", &content[]);
+        let destination = create(&dir, "tail-destination", &content[]);
+
+        let destinations = [destination.clone()];
+        let report = new_dedup(source.clone(), &destinations).perform().unwrap();
+
+        fs::unlink(&*source).unwrap();
+        fs::unlink(&*destination).unwrap();
+
+        let deduped = report.destinations[0].bytes_deduped;
+
+        assert_eq!(report.destinations[0].status, Ok(()));
+        assert_eq!(report.destinations[0].tail_error, None);
+        assert!(deduped == 64 * 1024 || deduped == content.len() as u64);
+    }
+
+    fn destination(name: &str) -> Destination {
+        Destination {
+            path: Arc::new(Path::new(name)),
+            fd: -1,
+            logical_offset: 0,
+            bytes_deduped: 0,
+            error: None,
+            tail_error: None,
         }
+    }
+
+    // The kernel is free to stop short on each destination separately
+    #[test]
+    fn test_stalled_destinations_dont_hold_back_others() {
+        let mut destinations = vec![destination("a"), destination("stalled"), destination("c")];
+        let mut done = vec![0u64, 0, 0];
+
+        assert_eq!(next_batch(&destinations[], &done[], 16384), Some((0, vec![0, 1, 2])));
+        record_progress(&mut destinations[], &mut done[], &[0, 1, 2], &[(0, 4096), (0, 0), (0, 8192)]);
+
+        assert_eq!(destinations[1].error, Some(DestinationError::Incomplete));
+
+        // The one behind goes first, and then they go on together
+        assert_eq!(next_batch(&destinations[], &done[], 16384), Some((4096, vec![0])));
+        record_progress(&mut destinations[], &mut done[], &[0], &[(0, 4096)]);
+
+        assert_eq!(next_batch(&destinations[], &done[], 16384), Some((8192, vec![0, 2])));
+        record_progress(&mut destinations[], &mut done[], &[0, 2], &[(0, 8192), (0, 8192)]);
 
-        total_dedup
+        assert_eq!(next_batch(&destinations[], &done[], 16384), None);
+
+        assert_eq!(destinations[0].bytes_deduped, 16384);
+        assert_eq!(destinations[1].bytes_deduped, 0);
+        assert_eq!(destinations[2].bytes_deduped, 16384);
+        assert_eq!(destinations[0].error, None);
+        assert_eq!(destinations[2].error, None);
+    }
+
+    // Older kernels turn it down for the whole call, newer ones for each destination
+    #[test]
+    fn test_partial_block_rejections() {
+        let einval = IoError::from_errno(22, false);
+
+        assert!(rejects_partial_blocks(&Some(DestinationError::CallFailed(einval))));
+        assert!(rejects_partial_blocks(&Some(DestinationError::Refused(22))));
+
+        assert!(!rejects_partial_blocks(&Some(DestinationError::Refused(1))));
+        assert!(!rejects_partial_blocks(&Some(DestinationError::DataDiffers)));
+        assert!(!rejects_partial_blocks(&None));
+    }
+
+    // Runs anywhere: nothing is asked of the kernel
+    #[test]
+    fn test_small_files_report_every_destination() {
+        let tempdir = TempDir::new("dedup").unwrap();
+        let content = content(100);
+
+        let source       = create(tempdir.path(), "small-source", &content[]);
+        let destinations = [
+            create(tempdir.path(), "small-a", &content[]),
+            create(tempdir.path(), "small-b", &content[]),
+        ];
+
+        let report = new_dedup(source, &destinations).perform().unwrap();
+
+        assert_eq!(report.calls, 0);
+        assert_eq!(report.destinations.len(), 2);
+
+        for (destination, path) in report.destinations.iter().zip(destinations.iter()) {
+            assert_eq!(destination.path, *path);
+            assert_eq!(destination.status, Err(DestinationError::TooSmall));
+        }
     }
 }
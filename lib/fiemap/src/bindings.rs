@@ -20,6 +20,7 @@ pub unsafe fn fiemap_ioctl(fd: c_int, map: &mut fiemap) -> IoResult<isize> {
 }
 
 #[repr(C)]
+#[derive(Clone)]
 #[allow(missing_copy_implementations)]
 pub struct fiemap_extent {
     /* logical offset in bytes for the start of the extent from the beginning of the file */
@@ -41,6 +42,18 @@ impl fiemap_extent {
     pub fn flags(&self) -> ExtentFlags {
         ExtentFlags::from_bits_truncate(self.fe_flags)
     }
+
+    pub fn logical(&self) -> u64 {
+        self.fe_logical
+    }
+
+    pub fn physical(&self) -> u64 {
+        self.fe_physical
+    }
+
+    pub fn length(&self) -> u64 {
+        self.fe_length
+    }
 }
 
 impl PartialEq for fiemap_extent {
@@ -8,9 +8,10 @@ extern crate ioctl;
 #[macro_use]
 extern crate bitflags;
 
-use std::old_io::File;
+use std::old_io::{File, IoResult};
 use std::os::unix::prelude::*;
-use bindings::FiemapRequest;
+use std::{cmp, mem, u64};
+use bindings::{FiemapRequest, fiemap, fiemap_extent};
 
 #[allow(non_camel_case_types)]
 pub mod bindings;
@@ -18,12 +19,25 @@ pub mod bindings;
 #[cfg(test)]
 mod test_helpers;
 
-pub fn compare(file1: &File, file2: &File) -> ComparisonResult {
-    let mut request1 = FiemapRequest::new(file1.as_raw_fd()).unwrap();
-    let mut request2 = FiemapRequest::new(file2.as_raw_fd()).unwrap();
+pub fn compare(file1: &File, file2: &File) -> IoResult<ComparisonResult> {
+    let extents1 = try!(extents(file1));
+    let extents2 = try!(extents(file2));
 
-    let extents1 = request1.extents();
-    let extents2 = request2.extents();
+    Ok(compare_extents(&extents1[], &extents2[]))
+}
+
+// All the extents of the file, to compare with `compare_extents` without mapping
+// them again each time
+pub fn extents(file: &File) -> IoResult<Vec<fiemap_extent>> {
+    let mut request = try!(FiemapRequest::new(file.as_raw_fd()));
+    Ok(request.extents().to_vec())
+}
+
+pub fn compare_extents(extents1: &[fiemap_extent], extents2: &[fiemap_extent]) -> ComparisonResult {
+    // Empty or fully sparse files share nothing
+    if extents1.is_empty() || extents2.is_empty() {
+        return ComparisonResult::NotDeduped;
+    }
 
     let (inits_match, lasts_match) = {
         let (init1, last1) = if extents1.len() == 1 {
@@ -52,6 +66,64 @@ pub fn compare(file1: &File, file2: &File) -> ComparisonResult {
     }
 }
 
+// Where the first extent of the file starts on disk, or `None` if the file has
+// no extents. Only that extent is mapped, so it's cheap even for fragmented files.
+pub fn first_physical_offset(file: &File) -> IoResult<Option<u64>> {
+    #[repr(C)]
+    struct SingleExtentRequest {
+        map: fiemap,
+        extent: fiemap_extent,
+    }
+
+    unsafe {
+        let mut request: SingleExtentRequest = mem::zeroed();
+        request.map.fm_length = u64::MAX;
+        request.map.fm_extent_count = 1;
+
+        try!(bindings::fiemap_ioctl(file.as_raw_fd(), &mut request.map));
+
+        if request.map.fm_mapped_extents == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(request.extent.physical()))
+        }
+    }
+}
+
+pub fn extent_count(file: &File) -> IoResult<usize> {
+    let mut request = try!(FiemapRequest::new(file.as_raw_fd()));
+    Ok(request.extents().len())
+}
+
+// Bytes of the file on extents that are shared with other files (or other parts
+// of the same file)
+pub fn shared_bytes(file: &File) -> IoResult<u64> {
+    let mut request = try!(FiemapRequest::new(file.as_raw_fd()));
+
+    let shared = request.extents().iter()
+        .filter(|extent| extent.flags().contains(bindings::extent_flags::SHARED))
+        .fold(0, |total, extent| total + extent.length());
+
+    Ok(shared)
+}
+
+// `shared_bytes`, only counting what falls within `length` bytes from `offset`
+pub fn shared_bytes_in(file: &File, offset: u64, length: u64) -> IoResult<u64> {
+    let mut request = try!(FiemapRequest::new(file.as_raw_fd()));
+    let end = offset + length;
+
+    let shared = request.extents().iter()
+        .filter(|extent| extent.flags().contains(bindings::extent_flags::SHARED))
+        .fold(0, |total, extent| {
+            let start = cmp::max(extent.logical(), offset);
+            let stop  = cmp::min(extent.logical() + extent.length(), end);
+
+            total + stop.saturating_sub(start)
+        });
+
+    Ok(shared)
+}
+
 #[derive(Show, PartialEq, Eq, Copy)]
 pub enum ComparisonResult {
     AlreadyDeduped,
@@ -80,7 +152,7 @@ mod tests {
 
         sync();
 
-        let result = compare(&result1.rtio_file, &result2.rtio_file);
+        let result = compare(&result1.rtio_file, &result2.rtio_file).unwrap();
         assert_eq!(result, NotDeduped);
     }
 
@@ -94,7 +166,7 @@ mod tests {
 
         let reflinked_file = open_for_inspection(& reflinked_path);
 
-        let result = compare(&tempfile_result.rtio_file, &reflinked_file);
+        let result = compare(&tempfile_result.rtio_file, &reflinked_file).unwrap();
         assert_eq!(result, AlreadyDeduped);
     }
 
@@ -24,6 +24,11 @@ pub fn ioc(dir: i32, ty: i32, nr: i32, size: i32) -> i32 {
     (size << IOC_SIZESHIFT)
 }
 
+#[inline]
+pub fn ior(magic: i32, nr: i32, size: usize) -> i32 {
+    ioc(IOC_READ, magic, nr, size as i32)
+}
+
 #[inline]
 pub fn iowr(magic: i32, nr: i32, size: usize) -> i32 {
     ioc(IOC_READ | IOC_WRITE, magic, nr, size as i32)
@@ -0,0 +1,453 @@
+use filehasher::{self, HashError, HashResult, IoStrategy};
+use throttle::Throttle;
+use signals;
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+
+use std::old_io::fs::PathExtensions;
+use std::thread::Thread;
+use std::sync::Arc;
+use std::{cmp, iter};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use deque::{self, BufferPool};
+
+#[derive(Clone, Show)]
+pub struct DuplicateRange {
+    pub source:             Arc<Path>,
+    pub source_offset:      u64,
+    pub destination:        Arc<Path>,
+    pub destination_offset: u64,
+    pub length:             u64,
+}
+
+// A run of all-zero blocks in a single file
+#[derive(Clone, Show)]
+pub struct ZeroRange {
+    pub path:   Arc<Path>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub enum BlockCheckResult {
+    Range(DuplicateRange),
+    Zeros(ZeroRange),
+
+    // The file couldn't be read, and is left out
+    Failed(Arc<Path>, HashError),
+}
+
+struct BlockJob {
+    file_id: usize,
+    path: Arc<Path>,
+}
+
+struct BlockJobResult {
+    file_id: usize,
+    fingerprints: HashResult<Vec<Fingerprint>>,
+}
+
+// The first 64 bits of a block's digest. Two blocks with the same fingerprint
+// aren't necessarily the same, but the kernel compares them before deduping.
+type Fingerprint = u64;
+
+// How many bits the seen filter takes for each block hashed, and how many of them
+// each fingerprint sets. About 1% of the blocks seen only once pass for repeated.
+const SEEN_BITS_PER_BLOCK: u64 = 10;
+const SEEN_HASHES: u64 = 4;
+
+// The fingerprints seen so far, as a Bloom filter: it can tell that one wasn't seen
+// yet, but may be wrong when it says it was
+struct SeenFilter {
+    bits: Vec<u64>,
+    mask: u64,
+}
+
+impl SeenFilter {
+    fn new(blocks: u64) -> SeenFilter {
+        let len = cmp::max(blocks * SEEN_BITS_PER_BLOCK, 64).next_power_of_two();
+
+        SeenFilter {
+            bits: iter::repeat(0).take((len / 64) as usize).collect(),
+            mask: len - 1,
+        }
+    }
+
+    // Returns whether `fingerprint` may have been seen before
+    fn insert(&mut self, fingerprint: Fingerprint) -> bool {
+        // Double hashing, from two halves of a remix of the fingerprint
+        let mixed  = (fingerprint ^ (fingerprint >> 31)).wrapping_mul(0x9E3779B97F4A7C15);
+        let first  = mixed & 0xFFFFFFFF;
+        let second = (mixed >> 32) | 1;
+
+        let mut seen = true;
+
+        for i in (0..SEEN_HASHES) {
+            let bit  = first.wrapping_add(i.wrapping_mul(second)) & self.mask;
+            let word = &mut self.bits[(bit / 64) as usize];
+
+            seen  = seen && *word & (1 << (bit % 64)) != 0;
+            *word = *word | (1 << (bit % 64));
+        }
+
+        seen
+    }
+}
+
+pub fn spawn_workers(
+    count: usize,
+    paths: Vec<Arc<Path>>,
+    block_size: usize,
+    io_strategy: IoStrategy,
+    buffer_size: usize,
+    read_throttle: Arc<Throttle>,
+    find_zeros: bool) -> Receiver<BlockCheckResult>
+{
+    let (results_tx, results_rx) = channel();
+
+    Thread::spawn(move || {
+        let pass = |&: file_ids: &[usize]| {
+            fingerprint_files(count, &paths[], file_ids, block_size, io_strategy, buffer_size, read_throttle.clone())
+        };
+
+        // Zero blocks are better off as holes than all sharing a single extent
+        let zero_fingerprint = fingerprint(&filehasher::zero_block_digest(block_size)[]);
+        let zero_fingerprint = if find_zeros { Some(zero_fingerprint) } else { None };
+
+        // Keeping every fingerprint would take memory in proportion to all the data
+        // looked at. Instead, a first pass only finds out which ones repeat, and a
+        // second one reads everything again, keeping just those.
+        let blocks = paths.iter().fold(0, |total, path| {
+            total + path.stat().map(|stat| stat.size).unwrap_or(0) / block_size as u64
+        });
+
+        let mut seen     = SeenFilter::new(blocks);
+        let mut repeated = HashSet::new();
+        let mut readable = Vec::new();
+
+        let all_files: Vec<usize> = (0..paths.len()).collect();
+
+        for job_result in pass(&all_files[]).iter() {
+            match job_result.fingerprints {
+                Ok(fingerprints) => {
+                    for &fingerprint in fingerprints.iter() {
+                        if seen.insert(fingerprint) { repeated.insert(fingerprint); }
+                    }
+
+                    readable.push(job_result.file_id);
+                },
+
+                Err(err) => {
+                    let path = paths[job_result.file_id].clone();
+                    results_tx.send(BlockCheckResult::Failed(path, err)).unwrap();
+                },
+            }
+        }
+
+        drop(seen);
+
+        if let Some(zero_fingerprint) = zero_fingerprint {
+            repeated.insert(zero_fingerprint);
+        }
+
+        let mut blocks_per_file: Vec<Vec<(u64, Fingerprint)>> = paths.iter().map(|_| Vec::new()).collect();
+
+        for job_result in pass(&readable[]).iter() {
+            match job_result.fingerprints {
+                Ok(fingerprints) => {
+                    blocks_per_file[job_result.file_id] = fingerprints.iter().enumerate()
+                        .filter(|&(_, fingerprint)| repeated.contains(fingerprint))
+                        .map(|(block, &fingerprint)| (block as u64, fingerprint))
+                        .collect();
+                },
+
+                // Something happened to it since the first pass
+                Err(err) => {
+                    let path = paths[job_result.file_id].clone();
+                    results_tx.send(BlockCheckResult::Failed(path, err)).unwrap();
+                },
+            }
+        }
+
+        drop(repeated);
+
+        if let Some(zero_fingerprint) = zero_fingerprint {
+            let runs = find_zero_runs(&blocks_per_file[], zero_fingerprint, &paths[], block_size as u64);
+
+            for range in runs.into_iter() {
+                results_tx.send(BlockCheckResult::Zeros(range)).unwrap();
+            }
+        }
+
+        let matches = find_matches(&blocks_per_file[], zero_fingerprint);
+        drop(blocks_per_file);
+
+        for range in merge_matches(matches, &paths[], block_size as u64).into_iter() {
+            results_tx.send(BlockCheckResult::Range(range)).unwrap();
+        }
+    });
+
+    results_rx
+}
+
+// Reads the files in `file_ids`, with `count` workers. Gives the fingerprints of
+// each, as its workers finish it.
+fn fingerprint_files(
+    count: usize,
+    paths: &[Arc<Path>],
+    file_ids: &[usize],
+    block_size: usize,
+    io_strategy: IoStrategy,
+    buffer_size: usize,
+    read_throttle: Arc<Throttle>) -> Receiver<BlockJobResult>
+{
+    let (job_results_tx, job_results_rx) = channel();
+
+    let pool = BufferPool::new();
+    let (w, stealer) = pool.deque();
+
+    for &file_id in file_ids.iter() {
+        w.push(BlockJob { file_id: file_id, path: paths[file_id].clone() });
+    }
+
+    for _ in (0..count) {
+        let stealer = stealer.clone();
+        let worker_job_results_tx = job_results_tx.clone();
+        let throttle = read_throttle.clone();
+
+        Thread::spawn(move || {
+            let hasher = filehasher::new(buffer_size, io_strategy, throttle);
+            worker(stealer, worker_job_results_tx, hasher, block_size)
+        });
+    }
+
+    job_results_rx
+}
+
+fn fingerprint(digest: &[u8]) -> Fingerprint {
+    digest[..8].iter().fold(0, |fingerprint, &byte| (fingerprint << 8) | byte as u64)
+}
+
+// The first occurrence of each block (in file order) is taken as the source for
+// all the others. Blocks are given as (block, fingerprint) pairs, in block order,
+// and may leave out any that aren't repeated. Blocks with the `skipped`
+// fingerprint are left out.
+fn find_matches(blocks_per_file: &[Vec<(u64, Fingerprint)>], skipped: Option<Fingerprint>) -> Vec<BlockMatch> {
+    let mut first_seen: HashMap<Fingerprint, (usize, u64)> = HashMap::new();
+    let mut matches = Vec::new();
+
+    for (file_id, blocks) in blocks_per_file.iter().enumerate() {
+        for &(block, fingerprint) in blocks.iter() {
+            if skipped == Some(fingerprint) { continue; }
+
+            match first_seen.entry(fingerprint) {
+                Entry::Vacant(entry) => {
+                    entry.insert((file_id, block));
+                },
+
+                Entry::Occupied(entry) => {
+                    let (source_file, source_block) = *entry.get();
+
+                    matches.push(BlockMatch {
+                        source_file:      source_file,
+                        destination_file: file_id,
+                        shift:            block as i64 - source_block as i64,
+                        source_block:     source_block,
+                    });
+                },
+            }
+        }
+    }
+
+    matches
+}
+
+// Blocks are given as in `find_matches`
+fn find_zero_runs(
+    blocks_per_file: &[Vec<(u64, Fingerprint)>],
+    zero_fingerprint: Fingerprint,
+    paths: &[Arc<Path>],
+    block_size: u64) -> Vec<ZeroRange>
+{
+    let mut ranges = Vec::new();
+
+    for (file_id, blocks) in blocks_per_file.iter().enumerate() {
+        // First and one past the last block of the run so far
+        let mut run: Option<(u64, u64)> = None;
+
+        // A sentinel at the end closes a run reaching the last block
+        let zero_blocks = blocks.iter()
+            .filter(|&&(_, fingerprint)| fingerprint == zero_fingerprint)
+            .map(|&(block, _)| Some(block))
+            .chain(Some(None).into_iter());
+
+        for block in zero_blocks {
+            match (run, block) {
+                (Some((start, end)), Some(block)) if block == end => run = Some((start, end + 1)),
+
+                (Some((start, end)), _) => {
+                    ranges.push(ZeroRange {
+                        path:   paths[file_id].clone(),
+                        offset: start * block_size,
+                        length: (end - start) * block_size,
+                    });
+
+                    run = block.map(|block| (block, block + 1));
+                },
+
+                (None, _) => run = block.map(|block| (block, block + 1)),
+            }
+        }
+    }
+
+    ranges
+}
+
+fn merge_matches(mut matches: Vec<BlockMatch>, paths: &[Arc<Path>], block_size: u64) -> Vec<DuplicateRange> {
+    matches.sort();
+
+    let mut ranges = Vec::new();
+    let mut iter   = matches.into_iter();
+
+    let mut current = match iter.next() {
+        Some(first) => first,
+        None        => return ranges,
+    };
+    let mut run_length = 1u64;
+
+    for next in iter {
+        let extends_run = next.source_file      == current.source_file &&
+                          next.destination_file == current.destination_file &&
+                          next.shift            == current.shift &&
+                          next.source_block     == current.source_block + run_length;
+
+        if extends_run {
+            run_length += 1;
+            continue;
+        }
+
+        ranges.push(to_range(&current, run_length, paths, block_size));
+
+        current    = next;
+        run_length = 1;
+    }
+
+    ranges.push(to_range(&current, run_length, paths, block_size));
+    ranges
+}
+
+fn to_range(first: &BlockMatch, run_length: u64, paths: &[Arc<Path>], block_size: u64) -> DuplicateRange {
+    let destination_block = (first.source_block as i64 + first.shift) as u64;
+
+    DuplicateRange {
+        source:             paths[first.source_file].clone(),
+        source_offset:      first.source_block * block_size,
+        destination:        paths[first.destination_file].clone(),
+        destination_offset: destination_block * block_size,
+        length:             run_length * block_size,
+    }
+}
+
+fn worker(
+    stealer: deque::Stealer<BlockJob>,
+    tx: Sender<BlockJobResult>,
+    mut hasher: filehasher::FileHasher,
+    block_size: usize)
+{
+    loop {
+        if signals::cancelled() { break; }
+
+        let BlockJob { file_id, path } = match stealer.steal() {
+            deque::Empty     => break,
+            deque::Abort     => continue,
+            deque::Data(job) => job,
+        };
+
+        let mut fingerprints = Vec::new();
+        let result = hasher.hash_blocks(&*path, block_size, |digest| fingerprints.push(fingerprint(digest)));
+
+        let fingerprints = result.map(|()| fingerprints);
+        tx.send(BlockJobResult { file_id: file_id, fingerprints: fingerprints }).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_matches, find_zero_runs, merge_matches, SeenFilter};
+    use std::sync::Arc;
+
+    // One block per character, with '.' for a block that isn't repeated and so
+    // was left out
+    fn blocks(names: &str) -> Vec<(u64, u64)> {
+        names.bytes().enumerate()
+            .filter(|&(_, name)| name != b'.')
+            .map(|(block, name)| (block as u64, name as u64))
+            .collect()
+    }
+
+    fn paths() -> Vec<Arc<Path>> {
+        vec![Arc::new(Path::new("/a")), Arc::new(Path::new("/b"))]
+    }
+
+    #[test]
+    fn test_finds_runs_at_different_offsets() {
+        let blocks = vec![blocks(".abcd"), blocks("..abc.")];
+        let ranges = merge_matches(find_matches(&blocks[], None), &paths()[], 4096);
+
+        let abc = ranges.iter().find(|range| range.length == 3 * 4096).unwrap();
+        assert_eq!(*abc.source, Path::new("/a"));
+        assert_eq!(abc.source_offset, 1 * 4096);
+        assert_eq!(*abc.destination, Path::new("/b"));
+        assert_eq!(abc.destination_offset, 2 * 4096);
+    }
+
+    #[test]
+    fn test_repeated_blocks_within_a_file() {
+        let blocks = vec![blocks("abab"), blocks("")];
+        let ranges = merge_matches(find_matches(&blocks[], None), &paths()[], 4096);
+
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].source_offset, 0);
+        assert_eq!(ranges[0].destination_offset, 2 * 4096);
+        assert_eq!(ranges[0].length, 2 * 4096);
+    }
+
+    #[test]
+    fn test_zero_blocks_become_runs_and_not_matches() {
+        let blocks = vec![blocks("a00b0"), blocks("00a")];
+
+        let zeros = find_zero_runs(&blocks[], b'0' as u64, &paths()[], 4096);
+        let spans: Vec<(u64, u64)> = zeros.iter().map(|range| (range.offset, range.length)).collect();
+        assert_eq!(spans, vec![(4096, 2 * 4096), (4 * 4096, 4096), (0, 2 * 4096)]);
+
+        let ranges = merge_matches(find_matches(&blocks[], Some(b'0' as u64)), &paths()[], 4096);
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(*ranges[0].destination, Path::new("/b"));
+    }
+
+    #[test]
+    fn test_zero_runs_break_at_left_out_blocks() {
+        let blocks = vec![blocks("0.00"), blocks("")];
+
+        let zeros = find_zero_runs(&blocks[], b'0' as u64, &paths()[], 4096);
+        let spans: Vec<(u64, u64)> = zeros.iter().map(|range| (range.offset, range.length)).collect();
+        assert_eq!(spans, vec![(0, 4096), (2 * 4096, 2 * 4096)]);
+    }
+
+    #[test]
+    fn test_no_matches() {
+        let blocks = vec![blocks("abc"), blocks("def")];
+        assert!(merge_matches(find_matches(&blocks[], None), &paths()[], 4096).is_empty());
+    }
+
+    #[test]
+    fn test_seen_filter_finds_every_repeat() {
+        let mut seen = SeenFilter::new(10000);
+
+        let false_repeats = (0..10000u64).filter(|&i| seen.insert(i * 0x100000001)).count();
+        assert!(false_repeats < 300);
+
+        assert!((0..10000u64).all(|i| seen.insert(i * 0x100000001)));
+    }
+}
@@ -0,0 +1,453 @@
+use block_check::DuplicateRange;
+use filehasher::{self, HashError, HashResult, IoStrategy};
+use throttle::Throttle;
+use signals;
+
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use std::thread::Thread;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::iter;
+
+use deque::{self, BufferPool};
+
+pub enum ChunkCheckResult {
+    Range(DuplicateRange),
+
+    // The file couldn't be read, and is left out
+    Failed(Arc<Path>, HashError),
+
+    // Sent once, after all ranges. `unaligned_bytes` is the part of `matched_bytes`
+    // that couldn't be turned into block-aligned ranges.
+    Totals { matched_bytes: u64, unaligned_bytes: u64 },
+}
+
+#[derive(Clone, Show)]
+pub struct Chunk {
+    pub offset: u64,
+    pub length: u64,
+    pub digest: Vec<u8>,
+}
+
+// Content-defined chunker using a gear rolling hash (as on FastCDC). Boundaries
+// depend only on the bytes right before them, so they realign after data is
+// inserted or removed.
+pub struct Chunker {
+    gear:     Vec<u64>,
+    mask:     u64,
+    min_size: usize,
+    max_size: usize,
+}
+
+impl Chunker {
+    pub fn new(average_size: usize) -> Chunker {
+        // splitmix64, with a fixed seed: boundaries must be the same for every file
+        let mut state = 0x2545F4914F6CDD1Du64;
+
+        let gear = (0..256).map(|_| {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            z ^ (z >> 31)
+        }).collect();
+
+        Chunker {
+            gear:     gear,
+            mask:     (average_size.next_power_of_two() - 1) as u64,
+            min_size: average_size / 4,
+            max_size: average_size * 8,
+        }
+    }
+
+    pub fn start(&self) -> Chunking {
+        Chunking {
+            chunker:     self,
+            hasher:      Md5::new(),
+            chunks:      Vec::new(),
+            hash:        0,
+            chunk_start: 0,
+            chunk_len:   0,
+        }
+    }
+}
+
+// The chunks of a file being fed piece by piece, in order
+pub struct Chunking<'a> {
+    chunker:     &'a Chunker,
+    hasher:      Md5,
+    chunks:      Vec<Chunk>,
+    hash:        u64,
+    chunk_start: u64,
+    chunk_len:   usize,
+}
+
+impl<'a> Chunking<'a> {
+    pub fn feed(&mut self, data: &[u8]) {
+        let chunker = self.chunker;
+
+        // Start of the bytes of `data` that weren't fed to the hasher yet
+        let mut pending = 0us;
+
+        for i in (0..data.len()) {
+            self.hash = (self.hash << 1).wrapping_add(chunker.gear[data[i] as usize]);
+            self.chunk_len += 1;
+
+            let at_boundary = self.chunk_len >= chunker.max_size ||
+                (self.chunk_len >= chunker.min_size && self.hash & chunker.mask == 0);
+
+            if !at_boundary { continue; }
+
+            self.hasher.input(&data[pending..i + 1]);
+            self.chunks.push(finish_chunk(&mut self.hasher, self.chunk_start, self.chunk_len));
+
+            pending           = i + 1;
+            self.chunk_start += self.chunk_len as u64;
+            self.chunk_len    = 0;
+            self.hash         = 0;
+        }
+
+        self.hasher.input(&data[pending..]);
+    }
+
+    pub fn finish(mut self) -> Vec<Chunk> {
+        if self.chunk_len > 0 {
+            let chunk = finish_chunk(&mut self.hasher, self.chunk_start, self.chunk_len);
+            self.chunks.push(chunk);
+        }
+
+        self.chunks
+    }
+}
+
+fn finish_chunk(hasher: &mut Md5, offset: u64, length: usize) -> Chunk {
+    let mut digest: Vec<_> = iter::repeat(0u8).take(hasher.output_bytes()).collect();
+    hasher.result(&mut digest[]);
+    hasher.reset();
+
+    Chunk { offset: offset, length: length as u64, digest: digest }
+}
+
+struct ChunkJob {
+    file_id: usize,
+    path: Arc<Path>,
+}
+
+struct ChunkJobResult {
+    file_id: usize,
+    chunks: HashResult<Vec<Chunk>>,
+}
+
+// A chunk that is identical to an earlier one (see block_check::BlockMatch)
+#[derive(PartialEq, Eq, PartialOrd, Ord, Show)]
+struct ChunkMatch {
+    source_file:      usize,
+    destination_file: usize,
+    shift:            i64,
+    source_chunk:     usize,
+}
+
+// A run of matching data, not necessarily aligned to anything
+#[derive(PartialEq, Show)]
+struct Region {
+    source_file:        usize,
+    source_offset:      u64,
+    destination_file:   usize,
+    destination_offset: u64,
+    length:             u64,
+}
+
+pub fn spawn_workers(
+    count: usize,
+    paths: Vec<Arc<Path>>,
+    average_chunk_size: usize,
+    block_size: usize,
+    io_strategy: IoStrategy,
+    buffer_size: usize,
+    read_throttle: Arc<Throttle>) -> Receiver<ChunkCheckResult>
+{
+    let (results_tx, results_rx) = channel();
+
+    Thread::spawn(move || {
+        let (job_results_tx, job_results_rx) = channel();
+
+        let pool = BufferPool::new();
+        let (w, stealer) = pool.deque();
+
+        for (file_id, path) in paths.iter().enumerate() {
+            w.push(ChunkJob { file_id: file_id, path: path.clone() });
+        }
+
+        let chunker = Arc::new(Chunker::new(average_chunk_size));
+
+        for _ in (0..count) {
+            let stealer = stealer.clone();
+            let chunker = chunker.clone();
+            let throttle = read_throttle.clone();
+            let worker_job_results_tx = job_results_tx.clone();
+
+            Thread::spawn(move || {
+                let hasher = filehasher::new(buffer_size, io_strategy, throttle);
+                worker(stealer, worker_job_results_tx, chunker, hasher)
+            });
+        }
+        drop(job_results_tx);
+
+        let mut chunks_per_file: Vec<Vec<Chunk>> = paths.iter().map(|_| Vec::new()).collect();
+
+        for job_result in job_results_rx.iter() {
+            match job_result.chunks {
+                Ok(chunks) => chunks_per_file[job_result.file_id] = chunks,
+
+                Err(err) => {
+                    let path = paths[job_result.file_id].clone();
+                    results_tx.send(ChunkCheckResult::Failed(path, err)).unwrap();
+                },
+            }
+        }
+
+        let regions = find_regions(&chunks_per_file[]);
+        send_ranges(regions, &paths[], block_size as u64, &results_tx);
+    });
+
+    results_rx
+}
+
+fn find_regions(chunks_per_file: &[Vec<Chunk>]) -> Vec<Region> {
+    let mut first_seen: HashMap<&[u8], (usize, usize)> = HashMap::new();
+    let mut matches = Vec::new();
+
+    for (file_id, chunks) in chunks_per_file.iter().enumerate() {
+        for (index, chunk) in chunks.iter().enumerate() {
+            match first_seen.entry(&chunk.digest[]) {
+                Entry::Vacant(entry) => {
+                    entry.insert((file_id, index));
+                },
+
+                Entry::Occupied(entry) => {
+                    let (source_file, source_chunk) = *entry.get();
+
+                    matches.push(ChunkMatch {
+                        source_file:      source_file,
+                        destination_file: file_id,
+                        shift:            index as i64 - source_chunk as i64,
+                        source_chunk:     source_chunk,
+                    });
+                },
+            }
+        }
+    }
+
+    matches.sort();
+
+    let mut regions: Vec<Region> = Vec::new();
+    let mut previous: Option<ChunkMatch> = None;
+
+    for current in matches.into_iter() {
+        let source_chunk      = &chunks_per_file[current.source_file][current.source_chunk];
+        let destination_index = (current.source_chunk as i64 + current.shift) as usize;
+        let destination_chunk = &chunks_per_file[current.destination_file][destination_index];
+
+        let extends_previous = match previous {
+            Some(ref previous) => {
+                previous.source_file      == current.source_file &&
+                previous.destination_file == current.destination_file &&
+                previous.shift            == current.shift &&
+                previous.source_chunk + 1 == current.source_chunk
+            },
+
+            None => false,
+        };
+
+        if extends_previous {
+            regions.last_mut().unwrap().length += source_chunk.length;
+        } else {
+            regions.push(Region {
+                source_file:        current.source_file,
+                source_offset:      source_chunk.offset,
+                destination_file:   current.destination_file,
+                destination_offset: destination_chunk.offset,
+                length:             source_chunk.length,
+            });
+        }
+
+        previous = Some(current);
+    }
+
+    regions
+}
+
+// Trims a region to the block-aligned range inside it. Both sides must be
+// aligned at the same time, so that's only possible when they are shifted by a
+// multiple of the block size.
+fn align(region: &Region, block_size: u64) -> Option<(u64, u64, u64)> {
+    let shift = region.destination_offset as i64 - region.source_offset as i64;
+
+    if shift % (block_size as i64) != 0 {
+        return None;
+    }
+
+    let start = (region.source_offset + block_size - 1) / block_size * block_size;
+    let end   = (region.source_offset + region.length) / block_size * block_size;
+
+    if end <= start {
+        return None;
+    }
+
+    Some((start, (start as i64 + shift) as u64, end - start))
+}
+
+fn send_ranges(
+    regions: Vec<Region>,
+    paths: &[Arc<Path>],
+    block_size: u64,
+    results_tx: &Sender<ChunkCheckResult>)
+{
+    let mut matched_bytes   = 0u64;
+    let mut unaligned_bytes = 0u64;
+
+    for region in regions.iter() {
+        matched_bytes += region.length;
+
+        let (source_offset, destination_offset, length) = match align(region, block_size) {
+            Some(aligned) => aligned,
+            None          => {
+                unaligned_bytes += region.length;
+                continue;
+            }
+        };
+
+        unaligned_bytes += region.length - length;
+
+        let range = DuplicateRange {
+            source:             paths[region.source_file].clone(),
+            source_offset:      source_offset,
+            destination:        paths[region.destination_file].clone(),
+            destination_offset: destination_offset,
+            length:             length,
+        };
+
+        results_tx.send(ChunkCheckResult::Range(range)).unwrap();
+    }
+
+    let totals = ChunkCheckResult::Totals {
+        matched_bytes:   matched_bytes,
+        unaligned_bytes: unaligned_bytes,
+    };
+
+    results_tx.send(totals).unwrap();
+}
+
+fn worker(
+    stealer: deque::Stealer<ChunkJob>,
+    tx: Sender<ChunkJobResult>,
+    chunker: Arc<Chunker>,
+    mut hasher: filehasher::FileHasher)
+{
+    loop {
+        if signals::cancelled() { break; }
+
+        let ChunkJob { file_id, path } = match stealer.steal() {
+            deque::Empty     => break,
+            deque::Abort     => continue,
+            deque::Data(job) => job,
+        };
+
+        let mut chunking = chunker.start();
+        let result = hasher.for_each_piece(&*path, |data| chunking.feed(data));
+
+        let chunks = result.map(|()| chunking.finish());
+        tx.send(ChunkJobResult { file_id: file_id, chunks: chunks }).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunker, Region, align, find_regions};
+    use std::iter::AdditiveIterator;
+
+    fn pseudo_random_data(len: usize) -> Vec<u8> {
+        let mut state = 12345u32;
+
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    // Fed in pieces that don't line up with any chunk
+    fn chunk(data: Vec<u8>) -> Vec<super::Chunk> {
+        let chunker = Chunker::new(4096);
+        let mut chunking = chunker.start();
+
+        for piece in data.chunks(1000) {
+            chunking.feed(piece);
+        }
+
+        chunking.finish()
+    }
+
+    #[test]
+    fn test_chunks_cover_the_whole_input() {
+        let chunks = chunk(pseudo_random_data(100_000));
+
+        let mut offset = 0;
+        for chunk in chunks.iter() {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.length <= 4096 * 8);
+            offset += chunk.length;
+        }
+
+        assert_eq!(offset, 100_000);
+    }
+
+    #[test]
+    fn test_boundaries_realign_after_insertion() {
+        let original = pseudo_random_data(200_000);
+
+        let mut shifted = vec![1u8, 2, 3];
+        shifted.push_all(&original[]);
+
+        let chunks = vec![chunk(original), chunk(shifted)];
+        let regions = find_regions(&chunks[]);
+
+        let matched: u64 = regions.iter()
+            .filter(|region| region.source_file == 0 && region.destination_file == 1)
+            .map(|region| region.length)
+            .sum();
+
+        assert!(matched > 150_000);
+    }
+
+    #[test]
+    fn test_align() {
+        let region = Region {
+            source_file: 0,
+            source_offset: 1000,
+            destination_file: 1,
+            destination_offset: 1000 + 4096,
+            length: 10_000,
+        };
+
+        assert_eq!(align(&region, 4096), Some((4096, 8192, 4096)));
+    }
+
+    #[test]
+    fn test_align_with_unaligned_shift() {
+        let region = Region {
+            source_file: 0,
+            source_offset: 0,
+            destination_file: 1,
+            destination_offset: 3,
+            length: 100_000,
+        };
+
+        assert_eq!(align(&region, 4096), None);
+    }
+}
@@ -0,0 +1,134 @@
+// Threads running whole-file dedups, so that the kernel compares and remaps the
+// extents of several groups at once instead of leaving the hash workers waiting
+// on a single thread. Groups go through a bounded queue, so that finding
+// duplicates doesn't get arbitrarily far ahead. Two groups sharing a file are
+// never deduped at the same time.
+
+use btrfs;
+use signals;
+use throttle::Throttle;
+
+use std::collections::HashSet;
+use std::old_io::fs::PathExtensions;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::Thread;
+
+// How many groups can be waiting for each worker
+const QUEUED_PER_WORKER: usize = 4;
+
+pub struct DedupGroup {
+    pub source:       Arc<Path>,
+    pub destinations: Vec<Arc<Path>>,
+}
+
+pub type Finished = (DedupGroup, btrfs::DedupResult);
+
+pub struct DedupPool {
+    groups_tx:  SyncSender<DedupGroup>,
+    results_rx: Receiver<Finished>,
+}
+
+// The files of the groups being deduped right now, by device and inode, so that
+// hardlinks and different spellings of the same path are seen as the same file
+struct InUse {
+    files:    Mutex<HashSet<(u64, u64)>>,
+    released: Condvar,
+}
+
+pub fn spawn(worker_count: usize, throttle: Arc<Throttle>) -> DedupPool {
+    let (groups_tx, groups_rx) = sync_channel(worker_count * QUEUED_PER_WORKER);
+    let (results_tx, results_rx) = channel();
+
+    let groups_rx = Arc::new(Mutex::new(groups_rx));
+    let in_use = Arc::new(InUse { files: Mutex::new(HashSet::new()), released: Condvar::new() });
+
+    for _ in 0..worker_count {
+        let groups_rx  = groups_rx.clone();
+        let results_tx = results_tx.clone();
+        let in_use     = in_use.clone();
+        let throttle   = throttle.clone();
+
+        Thread::spawn(move || worker(groups_rx, results_tx, in_use, throttle));
+    }
+
+    DedupPool { groups_tx: groups_tx, results_rx: results_rx }
+}
+
+impl DedupPool {
+    // Blocks while the queue is full. Returns the groups finished in the meantime.
+    pub fn submit(&self, group: DedupGroup) -> Vec<Finished> {
+        self.groups_tx.send(group).unwrap();
+
+        let mut finished = Vec::new();
+
+        while let Ok(result) = self.results_rx.try_recv() {
+            finished.push(result);
+        }
+
+        finished
+    }
+
+    // The groups still being deduped, as they finish
+    pub fn finish(self) -> Receiver<Finished> {
+        self.results_rx
+    }
+}
+
+impl InUse {
+    // Waits until none of `files` is being deduped, and then takes all of them at
+    // once, so that two workers can't each hold part of what the other needs
+    fn claim(&self, files: &[(u64, u64)]) {
+        let mut in_use = self.files.lock().unwrap();
+
+        while files.iter().any(|file| in_use.contains(file)) {
+            in_use = self.released.wait(in_use).unwrap();
+        }
+
+        in_use.extend(files.iter().cloned());
+    }
+
+    fn release(&self, files: &[(u64, u64)]) {
+        let mut in_use = self.files.lock().unwrap();
+
+        for file in files.iter() {
+            in_use.remove(file);
+        }
+
+        self.released.notify_all();
+    }
+}
+
+fn worker(
+    groups_rx: Arc<Mutex<Receiver<DedupGroup>>>,
+    results_tx: Sender<Finished>,
+    in_use: Arc<InUse>,
+    throttle: Arc<Throttle>
+) {
+    loop {
+        // Err means the pool was finished and the queue is empty
+        let group = match groups_rx.lock().unwrap().recv() {
+            Ok(group) => group,
+            Err(_)    => return,
+        };
+
+        // Queued groups are dropped, only the ones already running get to finish
+        if signals::cancelled() { continue; }
+
+        let size = group.source.stat().map(|stat| stat.size).unwrap_or(0);
+        throttle.acquire(size as usize * group.destinations.len());
+
+        // Files that can't be stated are left for the dedup to report
+        let files: Vec<(u64, u64)> = group.destinations.iter()
+            .chain(Some(&group.source).into_iter())
+            .filter_map(|path| path.stat().ok())
+            .map(|stat| (stat.unstable.device, stat.unstable.inode))
+            .collect();
+
+        in_use.claim(&files[]);
+        let result = btrfs::new_dedup(group.source.clone(), &group.destinations[]).perform();
+        in_use.release(&files[]);
+
+        if results_tx.send((group, result)).is_err() { return; }
+    }
+}
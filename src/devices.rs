@@ -0,0 +1,168 @@
+// Finds the block devices backing each file through sysfs, so reads can be
+// spread according to what each device can take.
+
+use btrfs;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::old_io::{File, IoResult};
+use std::old_io::fs::{self, PathExtensions};
+
+#[derive(Show, PartialEq, Copy)]
+pub enum DeviceKind {
+    // At least one of the backing devices is a spinning disk
+    Rotational,
+    Solid,
+
+    // Couldn't be found out (not a block device, no sysfs, ...)
+    Unknown,
+}
+
+// A set of devices that reads compete for: a whole btrfs filesystem (which
+// may span several disks), or a single block device otherwise
+#[derive(Clone, Show)]
+pub struct Device {
+    pub name: String,
+    pub kind: DeviceKind,
+}
+
+pub struct DeviceMap {
+    // Keyed by st_dev, which is shared by all the files of a filesystem (or
+    // a btrfs subvolume)
+    devices: HashMap<u64, Device>,
+}
+
+pub fn new_map() -> DeviceMap {
+    DeviceMap { devices: HashMap::new() }
+}
+
+impl DeviceMap {
+    pub fn device_of(&mut self, path: &Path) -> Device {
+        let dev = match path.stat() {
+            Ok(stat) => stat.unstable.device,
+            Err(err) => {
+                debug!("Couldn't stat {}: {}", path.display(), err);
+                return Device { name: "unknown".to_string(), kind: DeviceKind::Unknown };
+            }
+        };
+
+        match self.devices.entry(dev) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry)   => {
+                let device = find_device(path, dev);
+                info!("{} is on {} ({:?})", path.display(), device.name, device.kind);
+
+                entry.insert(device).clone()
+            }
+        }
+    }
+}
+
+fn find_device(path: &Path, dev: u64) -> Device {
+    // btrfs files live on an anonymous device, so their st_dev says nothing
+    // about the disks. The filesystem lists them on sysfs instead.
+    if let Ok(info) = File::open(path).and_then(|file| btrfs::fs_info(&file)) {
+        let fsid = format_uuid(&info.fsid);
+        let devices_dir = Path::new("/sys/fs/btrfs").join(&fsid[]).join("devices");
+
+        let kinds: Vec<DeviceKind> = match fs::readdir(&devices_dir) {
+            Ok(devices) => devices.iter().map(|device| kind_of(device)).collect(),
+            Err(err)    => {
+                debug!("Couldn't list the devices of {}: {}", fsid, err);
+                Vec::new()
+            }
+        };
+
+        return Device { name: fsid, kind: combined_kind(&kinds[]) };
+    }
+
+    let name = format!("{}:{}", major(dev), minor(dev));
+    let kind = kind_of(&Path::new("/sys/dev/block").join(&name[]));
+
+    Device { name: name, kind: kind }
+}
+
+// `device` is a sysfs symlink to the device directory. Partitions don't have
+// a queue of their own: it belongs to the parent disk.
+fn kind_of(device: &Path) -> DeviceKind {
+    let target = match fs::readlink(device) {
+        Ok(target) => device.dir_path().join(target),
+        Err(_)     => device.clone(),
+    };
+
+    for dir in [target.clone(), target.dir_path()].iter() {
+        match read_rotational(dir) {
+            Ok(true)  => return DeviceKind::Rotational,
+            Ok(false) => return DeviceKind::Solid,
+            Err(_)    => continue,
+        }
+    }
+
+    DeviceKind::Unknown
+}
+
+fn read_rotational(dir: &Path) -> IoResult<bool> {
+    let contents = try!(File::open(&dir.join("queue/rotational")).read_to_string());
+    Ok(contents.trim() == "1")
+}
+
+// A filesystem is as slow as its slowest device
+fn combined_kind(kinds: &[DeviceKind]) -> DeviceKind {
+    if kinds.is_empty() || kinds.iter().any(|&kind| kind == DeviceKind::Unknown) {
+        DeviceKind::Unknown
+    } else if kinds.iter().any(|&kind| kind == DeviceKind::Rotational) {
+        DeviceKind::Rotational
+    } else {
+        DeviceKind::Solid
+    }
+}
+
+// Same encoding as glibc's major() and minor()
+fn major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)
+}
+
+fn minor(dev: u64) -> u64 {
+    (dev & 0xff) | ((dev >> 12) & !0xff)
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("{}-{}-{}-{}-{}",
+            hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(),
+            hex[8..10].concat(), hex[10..16].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceKind, combined_kind, format_uuid, major, minor};
+
+    #[test]
+    fn test_format_uuid() {
+        let uuid = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef,
+                    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+
+        assert_eq!(format_uuid(&uuid), "01234567-89ab-cdef-0011-223344556677");
+    }
+
+    #[test]
+    fn test_device_numbers() {
+        // 259:65536, as encoded by glibc's makedev()
+        let dev = (259u64 << 8) | ((65536u64 & !0xff) << 12);
+
+        assert_eq!(major(dev), 259);
+        assert_eq!(minor(dev), 65536);
+        assert_eq!(major(0x0801), 8);
+        assert_eq!(minor(0x0801), 1);
+    }
+
+    #[test]
+    fn test_mixed_devices_are_rotational() {
+        let kinds = [DeviceKind::Solid, DeviceKind::Rotational];
+        assert_eq!(combined_kind(&kinds), DeviceKind::Rotational);
+
+        assert_eq!(combined_kind(&[DeviceKind::Solid]), DeviceKind::Solid);
+        assert_eq!(combined_kind(&[]), DeviceKind::Unknown);
+    }
+}
@@ -1,42 +1,431 @@
 use crypto::digest::Digest;
 use crypto::md5::Md5;
+use crypto::sha2::Sha256;
 
-use std::old_io::{File, IoError, EndOfFile};
-use std::iter;
+use libc::{self, c_int, c_void, off_t, size_t};
+
+use throttle::Throttle;
+use mapping::Mapping;
+
+use std::ffi::CString;
+use std::sync::Arc;
+use std::old_io::IoError;
+use std::rt::heap;
+use std::{cmp, iter, mem, os, raw};
+
+// O_DIRECT needs buffers (and reads) aligned to the logical block size of the device
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+// Not the same on every architecture
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const O_DIRECT: c_int = 0o40000;
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+const O_DIRECT: c_int = 0o200000;
+
+#[cfg(target_arch = "powerpc")]
+const O_DIRECT: c_int = 0o400000;
+
+#[cfg(target_arch = "mips")]
+const O_DIRECT: c_int = 0o100000;
+
+const POSIX_FADV_SEQUENTIAL: c_int = 2;
+const POSIX_FADV_DONTNEED:   c_int = 4;
+
+extern "C" {
+    fn posix_fadvise(fd: c_int, offset: off_t, len: off_t, advice: c_int) -> c_int;
+}
+
+#[derive(RustcDecodable, Show, PartialEq, Copy)]
+pub enum IoStrategy {
+    // Plain reads through the page cache
+    Buffered,
+
+    // O_DIRECT reads, bypassing the page cache
+    Direct,
+
+    // Reads through the page cache, with sequential readahead, dropping the
+    // pages behind the read cursor
+    Fadvise,
+
+    // Hashes straight from a mapping of the file, without copying it into a
+    // buffer first
+    Mmap,
+}
 
 pub struct FileHasher {
-    buffer: Vec<u8>,
-    hasher: Md5,
+    buffer: AlignedBuffer,
+    file_hasher: Sha256,
+    block_hasher: Md5,
+    strategy: IoStrategy,
+    throttle: Arc<Throttle>,
+}
+
+// An IO error, along with the errno that caused it
+pub struct HashError {
+    pub errno: i32,
+    pub error: IoError,
 }
 
+impl HashError {
+    pub fn from_errno(errno: i32) -> HashError {
+        HashError { errno: errno, error: IoError::from_errno(errno, true) }
+    }
+
+    // Must be called right after the failing syscall, before errno is overwritten
+    pub fn last() -> HashError {
+        HashError::from_errno(os::errno() as i32)
+    }
+}
+
+pub type HashResult<T> = Result<T, HashError>;
+
 impl FileHasher {
-    pub fn hash_whole_file(&mut self, mut file: File) -> Vec<u8> {
-        self.hasher.reset();
+    pub fn hash_whole_file(&mut self, path: &Path) -> HashResult<Vec<u8>> {
+        let mut file = try!(self.open(path));
+        self.file_hasher.reset();
+
+        {
+            let hasher = &mut self.file_hasher;
+            try!(file.for_each_piece(&mut self.buffer, &*self.throttle, |data| hasher.input(data)));
+        }
+
+        Ok(whole_file_digest(&mut self.file_hasher))
+    }
+
+    // Calls `f` with the digest of each full `block_size` block of the file, in
+    // order. A partial block at the end of the file is left out.
+    pub fn hash_blocks<F: FnMut(&[u8])>(&mut self, path: &Path, block_size: usize, mut f: F) -> HashResult<()> {
+        let mut file = try!(self.open(path));
+        self.block_hasher.reset();
+
+        let mut filled = 0us;
+        let hasher     = &mut self.block_hasher;
+
+        file.for_each_piece(&mut self.buffer, &*self.throttle, |mut data| {
+            while !data.is_empty() {
+                let taken = cmp::min(block_size - filled, data.len());
+
+                hasher.input(&data[..taken]);
+                filled += taken;
+                data = &data[taken..];
+
+                if filled == block_size {
+                    f(&finish_digest(hasher)[]);
+                    filled = 0;
+                }
+            }
+        })
+    }
+
+    // Calls `f` with consecutive pieces of the file, for readers that aren't after
+    // a plain digest
+    pub fn for_each_piece<F: FnMut(&[u8])>(&mut self, path: &Path, f: F) -> HashResult<()> {
+        let mut file = try!(self.open(path));
+        file.for_each_piece(&mut self.buffer, &*self.throttle, f)
+    }
+
+    fn open(&self, path: &Path) -> HashResult<OpenFile> {
+        match self.strategy {
+            IoStrategy::Buffered | IoStrategy::Direct | IoStrategy::Fadvise => {
+                RawFile::open(path, self.strategy).map(OpenFile::Raw)
+            },
+
+            IoStrategy::Mmap => Mapping::open(path).map(OpenFile::Mapped),
+        }
+    }
+}
+
+// The final digest of a whole file, fed to `hasher`. SHA256, so that hashfiles we
+// write are usable by duperemove.
+pub fn whole_file_digest(hasher: &mut Sha256) -> Vec<u8> {
+    let mut result: Vec<_> = iter::repeat(0u8).take(hasher.output_bytes()).collect();
+    hasher.result(&mut result[]);
+
+    result
+}
+
+// What `hash_blocks` yields for a block of zeros
+pub fn zero_block_digest(block_size: usize) -> Vec<u8> {
+    let zeros: Vec<u8> = iter::repeat(0u8).take(block_size).collect();
+
+    let mut hasher = Md5::new();
+    hasher.input(&zeros[]);
+
+    finish_digest(&mut hasher)
+}
+
+fn finish_digest(hasher: &mut Md5) -> Vec<u8> {
+    let mut digest: Vec<_> = iter::repeat(0u8).take(hasher.output_bytes()).collect();
+    hasher.result(&mut digest[]);
+    hasher.reset();
+
+    digest
+}
+
+// `buffer_size` must be a multiple of 4096 for the direct strategy
+pub fn new(buffer_size: usize, strategy: IoStrategy, throttle: Arc<Throttle>) -> FileHasher {
+    FileHasher {
+        buffer: AlignedBuffer::new(buffer_size),
+        file_hasher: Sha256::new(),
+        block_hasher: Md5::new(),
+        strategy: strategy,
+        throttle: throttle,
+    }
+}
+
+// Files are read with plain syscalls rather than old_io, so that the errno of a
+// failed read is still at hand
+enum OpenFile {
+    Raw(RawFile),
+    Mapped(Mapping),
+}
+
+impl OpenFile {
+    // Calls `f` with consecutive pieces of the file, of at most the buffer's size
+    fn for_each_piece<F: FnMut(&[u8])>(
+        &mut self,
+        buffer: &mut AlignedBuffer,
+        throttle: &Throttle,
+        mut f: F) -> HashResult<()>
+    {
+        let file = match *self {
+            OpenFile::Raw(ref mut file) => file,
+
+            OpenFile::Mapped(ref mapping) => {
+                return mapping.for_each_piece(buffer.len, |data| {
+                    throttle.acquire(data.len());
+                    f(data);
+                });
+            },
+        };
 
         loop {
-            match file.read(&mut self.buffer[]) {
-                Ok(count) => self.hasher.input(&self.buffer[..count]),
+            let count = try!(file.read(buffer.as_mut_slice()));
+            if count == 0 { break; }
+
+            throttle.acquire(count);
+            f(&buffer.as_slice()[..count]);
+        }
+
+        Ok(())
+    }
+}
+
+// A file read with plain syscalls
+pub struct RawFile {
+    fd: c_int,
+    position: u64,
+    drop_behind: bool,
+}
+
+impl RawFile {
+    pub fn open(path: &Path, strategy: IoStrategy) -> HashResult<RawFile> {
+        let (flags, drop_behind) = match strategy {
+            // Mappings are only used by FileHasher; anyone else just reads
+            IoStrategy::Buffered | IoStrategy::Mmap => (0, false),
+            IoStrategy::Direct   => (O_DIRECT, false),
+            IoStrategy::Fadvise  => (0, true),
+        };
 
-                Err(IoError { kind: EndOfFile, ..}) => break,
-                Err(err) => panic!("Error while hashing file: {}", err),
+        let c_path = CString::from_slice(path.as_vec());
+
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY | flags, 0) };
+
+        if fd < 0 {
+            return Err(HashError::last());
+        }
+
+        if drop_behind {
+            // Only advice, failing is harmless
+            unsafe { posix_fadvise(fd, 0, 0, POSIX_FADV_SEQUENTIAL); }
+        }
+
+        Ok(RawFile { fd: fd, position: 0, drop_behind: drop_behind })
+    }
+
+    // Returns 0 at the end of the file
+    fn read(&mut self, buffer: &mut [u8]) -> HashResult<usize> {
+        let count = unsafe {
+            libc::read(self.fd, buffer.as_mut_ptr() as *mut c_void, buffer.len() as size_t)
+        };
+
+        if count < 0 {
+            return Err(HashError::last());
+        }
+
+        self.consumed(count as usize);
+        Ok(count as usize)
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // Must be called after `count` bytes were read (by whatever means) from the
+    // current position
+    pub fn consumed(&mut self, count: usize) {
+        if self.drop_behind && count > 0 {
+            unsafe {
+                posix_fadvise(self.fd, self.position as off_t, count as off_t, POSIX_FADV_DONTNEED);
             }
         }
 
-        let block_size = self.hasher.block_size();
+        self.position += count as u64;
+    }
+}
 
-        let mut result: Vec<_> = iter::repeat(0u8).take(block_size).collect();
-        self.hasher.result(&mut result[]);
+impl Drop for RawFile {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
 
-        result
+pub struct AlignedBuffer {
+    allocation: *mut u8,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> AlignedBuffer {
+        let allocation = unsafe { heap::allocate(len, DIRECT_IO_ALIGNMENT) };
+        AlignedBuffer { allocation: allocation, len: len }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            mem::transmute(raw::Slice {
+                data: self.allocation as *const u8,
+                len: self.len,
+            })
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            mem::transmute(raw::Slice {
+                data: self.allocation as *const u8,
+                len: self.len,
+            })
+        }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            heap::deallocate(self.allocation, self.len, DIRECT_IO_ALIGNMENT);
+        }
     }
 }
 
-pub fn new(buffer_size: usize) -> FileHasher {
-    let mut buffer = Vec::with_capacity(buffer_size);
-    unsafe { buffer.set_len(buffer_size) }
+#[cfg(test)]
+mod tests {
+    use super::{new, IoStrategy, HashResult};
+    use throttle::Throttle;
 
-    FileHasher {
-        buffer: buffer,
-        hasher: Md5::new(),
+    use crypto::digest::Digest;
+    use crypto::md5::Md5;
+    use crypto::sha2::Sha256;
+
+    use std::old_io::{File, TempDir};
+    use std::sync::Arc;
+
+    const BUFFER_SIZE: usize = 8192;
+
+    const STRATEGIES: [IoStrategy; 4] = [
+        IoStrategy::Buffered,
+        IoStrategy::Direct,
+        IoStrategy::Fadvise,
+        IoStrategy::Mmap,
+    ];
+
+    const ENOENT: i32 = 2;
+    const EINVAL: i32 = 22;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn digest<D: Digest>(mut hasher: D, data: &[u8]) -> Vec<u8> {
+        hasher.input(data);
+
+        let mut digest: Vec<u8> = (0..hasher.output_bytes()).map(|_| 0u8).collect();
+        hasher.result(&mut digest[]);
+        digest
+    }
+
+    // Filesystems such as tmpfs refuse O_DIRECT, which isn't what's being tested
+    fn unless_unsupported<T>(strategy: IoStrategy, result: HashResult<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(ref err) if strategy == IoStrategy::Direct && err.errno == EINVAL => None,
+            Err(err) => panic!("{:?} failed: {} (errno {})", strategy, err.error, err.errno),
+        }
+    }
+
+    #[test]
+    fn test_every_strategy_gives_the_same_digest() {
+        let tempdir = TempDir::new("filehasher").unwrap();
+
+        // Empty, smaller than the buffer, a multiple of it, and not a multiple of it
+        for &len in [0, 100, 3 * BUFFER_SIZE, 3 * BUFFER_SIZE + 1234].iter() {
+            let content = content(len);
+            let path = tempdir.path().join(format!("file-{}", len));
+            File::create(&path).write_all(&content[]).unwrap();
+
+            for &strategy in STRATEGIES.iter() {
+                let mut hasher = new(BUFFER_SIZE, strategy, Arc::new(Throttle::new(None)));
+
+                if let Some(file_digest) = unless_unsupported(strategy, hasher.hash_whole_file(&path)) {
+                    assert_eq!(digest(Sha256::new(), &content[]), file_digest);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_strategy_gives_the_same_block_digests() {
+        let tempdir = TempDir::new("filehasher").unwrap();
+
+        let content = content(3 * BUFFER_SIZE + 1234);
+        let path = tempdir.path().join("file");
+        File::create(&path).write_all(&content[]).unwrap();
+
+        // The partial block at the end is left out
+        let expected: Vec<Vec<u8>> = content.chunks(4096)
+            .filter(|block| block.len() == 4096)
+            .map(|block| digest(Md5::new(), block))
+            .collect();
+
+        for &strategy in STRATEGIES.iter() {
+            let mut hasher = new(BUFFER_SIZE, strategy, Arc::new(Throttle::new(None)));
+
+            let mut digests = Vec::new();
+            let result = hasher.hash_blocks(&path, 4096, |digest| digests.push(digest.to_vec()));
+
+            if unless_unsupported(strategy, result).is_some() {
+                assert_eq!(expected, digests);
+            }
+        }
+    }
+
+    #[test]
+    fn test_errors_keep_their_errno() {
+        let tempdir = TempDir::new("filehasher").unwrap();
+        let missing = tempdir.path().join("missing");
+
+        for &strategy in STRATEGIES.iter() {
+            let mut hasher = new(BUFFER_SIZE, strategy, Arc::new(Throttle::new(None)));
+
+            match hasher.hash_whole_file(&missing) {
+                Err(err) => assert_eq!(err.errno, ENOENT),
+                Ok(_)    => panic!("{:?} read a missing file", strategy),
+            }
+        }
     }
 }
@@ -1,21 +1,108 @@
-use filehasher;
-
-use std::collections::BTreeMap;
+use filehasher::{self, HashError, IoStrategy, RawFile, AlignedBuffer};
+use uring::{self, IoVec};
+use throttle::Throttle;
+use signals;
+use devices::{self, DeviceKind, DeviceMap};
+use btrfs;
+use fiemap::{self, ComparisonResult};
+use fiemap::bindings::{extent_flags, fiemap_extent};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use libc::c_void;
+
+use std::collections::{BTreeMap, HashMap};
 use std::collections::VecMap;
+use std::collections::btree_map;
+use std::collections::hash_map::Entry;
 
 use std::thread::Thread;
 use std::sync::Arc;
+use std::{mem, u64};
 use std::sync::mpsc::{channel, Receiver, Sender};
 
 use deque::{self, BufferPool};
-use std::old_io::{IoError, File};
+use std::old_io::File;
+
+// Hash jobs are queued by the device holding the file, so that each device
+// gets its own set of workers
+struct DeviceQueues {
+    pool: BufferPool<DigestJob>,
+    devices: DeviceMap,
+    queues: HashMap<String, DeviceQueue>,
+    order_by_offset: bool,
+}
+
+struct DeviceQueue {
+    kind: DeviceKind,
+    worker: deque::Worker<DigestJob>,
+    stealer: deque::Stealer<DigestJob>,
+
+    // Jobs held back until all are known, to be queued by physical offset
+    pending: Vec<(u64, DigestJob)>,
+}
+
+impl DeviceQueues {
+    fn push(&mut self, job: DigestJob) {
+        let device = self.devices.device_of(&*job.path);
+
+        if !self.queues.contains_key(&device.name) {
+            let (worker, stealer) = self.pool.deque();
+            let queue = DeviceQueue {
+                kind: device.kind,
+                worker: worker,
+                stealer: stealer,
+                pending: Vec::new(),
+            };
+
+            self.queues.insert(device.name.clone(), queue);
+        }
+
+        let queue = self.queues.get_mut(&device.name).unwrap();
+
+        if self.order_by_offset {
+            let offset = physical_offset(&*job.path);
+            queue.pending.push((offset, job));
+        } else {
+            queue.worker.push(job);
+        }
+    }
 
-const BUFFER_SIZE:  usize = 64 * 1024;
+    // Workers steal jobs in the order they were pushed
+    fn flush_pending(&mut self) {
+        for (_, queue) in self.queues.iter_mut() {
+            let mut pending = mem::replace(&mut queue.pending, Vec::new());
+            pending.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+
+            for (_, job) in pending.into_iter() {
+                queue.worker.push(job);
+            }
+        }
+    }
+}
+
+// Files whose layout can't be found out go last
+fn physical_offset(path: &Path) -> u64 {
+    let offset = File::open(path).and_then(|file| fiemap::first_physical_offset(&file));
+
+    match offset {
+        Ok(Some(offset)) => offset,
+        Ok(None)         => u64::MAX,
+        Err(err)         => {
+            debug!("Couldn't map the extents of {}: {}", path.display(), err);
+            u64::MAX
+        }
+    }
+}
 
 struct SizeGroup {
     paths: Vec<Arc<Path>>,
     paths_per_digest: BTreeMap<Vec<u8>, Vec<usize>>,
     remaining: usize,
+
+    // Members that already share all their extents with another member (the
+    // key), and so weren't read
+    folded: HashMap<usize, Vec<usize>>,
 }
 
 struct DigestJob {
@@ -30,10 +117,59 @@ struct DigestJobResult {
 
 enum DigestResult {
     Successful(Vec<u8>),
-    Error(IoError),
+    Error(HashError),
+}
+
+pub enum CheckResult {
+    // A digest that was computed by the workers (precomputed ones aren't reported back)
+    Digest(Arc<Path>, Vec<u8>),
+    Duplicates(Vec<Arc<Path>>),
+
+    // Files that already share all their extents, found without reading them
+    AlreadyDeduped(Vec<Arc<Path>>),
+
+    // The file couldn't be read. Its group is completed without it.
+    Failed(Arc<Path>, HashError),
+}
+
+pub type Precomputed = HashMap<Path, Vec<u8>>;
+
+#[derive(RustcDecodable, Show, PartialEq, Copy)]
+pub enum IoBackend {
+    // One blocking read at a time on each worker thread
+    Threads,
+
+    // Many reads in flight on each worker thread, through io_uring. Falls back
+    // to `Threads` when io_uring isn't available.
+    Uring,
 }
 
-pub fn spawn_workers<Iter>(count: usize, iter: Iter) -> Receiver<Vec<Arc<Path>>>
+pub struct Options {
+    // Workers for each device, by kind. `worker_count` is used for devices of
+    // unknown kind.
+    pub worker_count: usize,
+    pub hdd_workers:  usize,
+    pub ssd_workers:  usize,
+
+    pub io_strategy:  IoStrategy,
+    pub buffer_size:  usize,
+    pub io_backend:   IoBackend,
+
+    // Files being read at the same time by each worker, on the uring backend
+    pub queue_depth:  usize,
+
+    pub read_throttle: Arc<Throttle>,
+
+    // Compare the checksums btrfs keeps for each data block before reading any
+    // file, and skip files whose checksums don't match any other's
+    pub use_csums: bool,
+
+    // Read the files of each device in the order of their first extent on disk,
+    // rather than by size group, to keep disk heads from seeking back and forth
+    pub order_by_offset: bool,
+}
+
+pub fn spawn_workers<Iter>(options: Options, iter: Iter, precomputed: Precomputed) -> Receiver<CheckResult>
     where Iter: Iterator<Item = Vec<Arc<Path>>> + Send
 {
     let (results_tx, results_rx) = channel();
@@ -41,16 +177,28 @@ pub fn spawn_workers<Iter>(count: usize, iter: Iter) -> Receiver<Vec<Arc<Path>>>
     Thread::spawn(move || {
         let (job_results_tx, job_results_rx) = channel();
 
-        let pool = BufferPool::new();
-        let (w, stealer) = pool.deque();
+        let mut queues = DeviceQueues {
+            pool: BufferPool::new(),
+            devices: devices::new_map(),
+            queues: HashMap::new(),
+            order_by_offset: options.order_by_offset,
+        };
+
+        let size_groups = seed_workers(&mut queues, iter, &options, &precomputed, &results_tx);
+        queues.flush_pending();
 
-        let size_groups = seed_workers(w, iter);
+        for (name, queue) in queues.queues.iter() {
+            let count = match queue.kind {
+                DeviceKind::Rotational => options.hdd_workers,
+                DeviceKind::Solid      => options.ssd_workers,
+                DeviceKind::Unknown    => options.worker_count,
+            };
 
-        for _ in (0..count) {
-            let stealer = stealer.clone();
-            let worker_job_results_tx = job_results_tx.clone();
+            info!("Using {} workers for {} ({:?})", count, name, queue.kind);
 
-            Thread::spawn(move || worker(stealer, worker_job_results_tx));
+            for _ in (0..count) {
+                spawn_worker(queue.stealer.clone(), job_results_tx.clone(), &options);
+            }
         }
         drop(job_results_tx);
 
@@ -60,11 +208,49 @@ pub fn spawn_workers<Iter>(count: usize, iter: Iter) -> Receiver<Vec<Arc<Path>>>
     results_rx
 }
 
+fn spawn_worker(stealer: deque::Stealer<DigestJob>, tx: Sender<DigestJobResult>, options: &Options) {
+    let (buffer_size, io_strategy) = (options.buffer_size, options.io_strategy);
+    let (io_backend, queue_depth)  = (options.io_backend, options.queue_depth);
+    let throttle = options.read_throttle.clone();
+
+    Thread::spawn(move || {
+        if io_backend == IoBackend::Uring {
+            // Each file has at most one read in flight
+            match uring::Ring::new(queue_depth as u32) {
+                Ok(ring) => {
+                    let slots = (0..queue_depth).map(|_| None).collect();
+                    let iovecs = (0..queue_depth).map(|_| {
+                        IoVec { base: 0 as *mut c_void, len: 0 }
+                    }).collect();
+
+                    let state = UringWorker {
+                        ring: ring,
+                        slots: slots,
+                        iovecs: iovecs,
+                        buffer_size: buffer_size,
+                        io_strategy: io_strategy,
+                        throttle: throttle,
+                    };
+
+                    return uring_worker(stealer, tx, state);
+                },
+
+                Err(err) => {
+                    info!("io_uring isn't available ({}), falling back to blocking reads", err);
+                }
+            }
+        }
+
+        let hasher = filehasher::new(buffer_size, io_strategy, throttle);
+        worker(stealer, tx, hasher)
+    });
+}
+
 
 fn listen_for_responses(
     mut size_groups: VecMap<SizeGroup>,
     job_results_rx: Receiver<DigestJobResult>,
-    results_tx: Sender<Vec<Arc<Path>>>)
+    results_tx: Sender<CheckResult>)
 {
     for job_result in job_results_rx.iter() {
         let (group_id, path_id) = job_result.id;
@@ -76,6 +262,9 @@ fn listen_for_responses(
 
             match job_result.result {
                 DigestResult::Successful(digest) => {
+                    let path = group.paths[path_id].clone();
+                    results_tx.send(CheckResult::Digest(path, digest.clone())).unwrap();
+
                     let ref mut map = group.paths_per_digest;
 
                     let added = match map.get_mut(&digest) {
@@ -93,7 +282,8 @@ fn listen_for_responses(
                 },
 
                 DigestResult::Error(err) => {
-                    error!("Error while trying to digest path: {}", err);
+                    let path = group.paths[path_id].clone();
+                    results_tx.send(CheckResult::Failed(path, err)).unwrap();
                 }
             }
 
@@ -105,70 +295,355 @@ fn listen_for_responses(
             continue;
         } else {
             let group = size_groups.remove(&group_id).unwrap();
+            send_duplicates(&group, &results_tx);
+        }
+    }
+}
+
+// Files folded into any member go along with it
+fn send_duplicates(group: &SizeGroup, results_tx: &Sender<CheckResult>) {
+    for (_, path_ids) in group.paths_per_digest.iter() {
+        if path_ids.len() < 2 { continue; }
 
-            for (_, path_ids) in group.paths_per_digest.iter() {
-                if path_ids.len() < 2 { continue; }
+        let mut paths = Vec::new();
 
-                let paths: Vec<Arc<Path>> = path_ids.iter().map(|&path_id| {
-                    group.paths[path_id].clone()
-                }).collect();
+        for &path_id in path_ids.iter() {
+            paths.push(group.paths[path_id].clone());
 
-                results_tx.send(paths).unwrap();
+            if let Some(members) = group.folded.get(&path_id) {
+                paths.extend(members.iter().map(|&member| group.paths[member].clone()));
             }
         }
+
+        results_tx.send(CheckResult::Duplicates(paths)).unwrap();
     }
 }
 
-fn seed_workers<Iter>(worker: deque::Worker<DigestJob>, iter: Iter) -> VecMap<SizeGroup>
-    where Iter: Iterator<Item = Vec<Arc<Path>>> + Send
-{
-    let mut size_groups: VecMap<SizeGroup>;
+// Bounds the comparisons made for each file on huge groups
+const MAX_REPRESENTATIVES: usize = 64;
 
-    size_groups = iter.enumerate().map(|(group_id, paths)| {
-        {
-            for (path_id, path) in paths.iter().enumerate() {
-                let job = DigestJob {
-                    id: (group_id, path_id),
-                    path: path.clone(),
-                };
+// For each member, the earlier member whose extents it already shares in full, if
+// any. Those have the same contents without having to read them. Each file's
+// extents are mapped once, and files without shared extents are skipped right
+// away, as nothing can share all of their extents.
+fn fold_shared(paths: &[Arc<Path>]) -> Vec<Option<usize>> {
+    let mut representatives: Vec<(usize, Vec<fiemap_extent>)> = Vec::new();
+    let mut folded_into = Vec::with_capacity(paths.len());
+
+    for (path_id, path) in paths.iter().enumerate() {
+        let extents = match File::open(&**path).and_then(|file| fiemap::extents(&file)) {
+            Ok(extents) => extents,
 
-                worker.push(job);
+            // Left for the workers to report
+            Err(err) => {
+                debug!("Couldn't map the extents of {}: {}", path.display(), err);
+                folded_into.push(None);
+                continue;
             }
+        };
+
+        if !extents.iter().any(|extent| extent.flags().contains(extent_flags::SHARED)) {
+            folded_into.push(None);
+            continue;
         }
 
-        let group = SizeGroup {
+        let representative = representatives.iter().find(|&&(_, ref representative_extents)| {
+            let comparison = fiemap::compare_extents(&representative_extents[], &extents[]);
+            comparison == ComparisonResult::AlreadyDeduped
+        }).map(|&(representative_id, _)| representative_id);
+
+        if representative.is_none() && representatives.len() < MAX_REPRESENTATIVES {
+            representatives.push((path_id, extents));
+        }
+
+        folded_into.push(representative);
+    }
+
+    folded_into
+}
+
+fn seed_workers<Iter>(
+    queues: &mut DeviceQueues,
+    iter: Iter,
+    options: &Options,
+    precomputed: &Precomputed,
+    results_tx: &Sender<CheckResult>) -> VecMap<SizeGroup>
+
+    where Iter: Iterator<Item = Vec<Arc<Path>>> + Send
+{
+    let mut size_groups = VecMap::new();
+
+    for (group_id, paths) in iter.enumerate() {
+        if signals::cancelled() { break; }
+
+        let mut group = SizeGroup {
             remaining: paths.len(),
             paths: paths,
-            paths_per_digest: BTreeMap::new()
+            paths_per_digest: BTreeMap::new(),
+            folded: HashMap::new(),
         };
 
-        (group_id, group)
-    }).collect();
+        // Precomputed digests may come from another hash function (or another
+        // tool altogether), so they're only compared with each other. That makes
+        // them usable only when every member of the group has one.
+        if group.paths.iter().all(|path| precomputed.contains_key(&**path)) {
+            for (path_id, path) in group.paths.iter().enumerate() {
+                let digest = precomputed.get(&**path).unwrap().clone();
+
+                match group.paths_per_digest.entry(digest) {
+                    btree_map::Entry::Vacant(entry)   => { entry.insert(vec![path_id]); },
+                    btree_map::Entry::Occupied(entry) => { entry.into_mut().push(path_id); },
+                }
+            }
+
+            send_duplicates(&group, results_tx);
+            continue;
+        }
+
+        let folded_into = fold_shared(&group.paths[]);
+
+        for (path_id, representative) in folded_into.iter().enumerate() {
+            if let Some(representative) = *representative {
+                match group.folded.entry(representative) {
+                    Entry::Vacant(entry)   => { entry.insert(vec![path_id]); },
+                    Entry::Occupied(entry) => { entry.into_mut().push(path_id); },
+                }
+            }
+        }
+
+        for (&representative, members) in group.folded.iter() {
+            let mut paths = vec![group.paths[representative].clone()];
+            paths.extend(members.iter().map(|&path_id| group.paths[path_id].clone()));
+
+            results_tx.send(CheckResult::AlreadyDeduped(paths)).unwrap();
+        }
+
+        let representatives: Vec<usize> = (0..group.paths.len())
+            .filter(|&path_id| folded_into[path_id].is_none())
+            .collect();
+
+        let mut needs_reading: Vec<bool> = group.paths.iter().map(|_| false).collect();
+
+        let representatives_reading = if options.use_csums {
+            let paths: Vec<Arc<Path>> = representatives.iter()
+                .map(|&path_id| group.paths[path_id].clone())
+                .collect();
+
+            csum_prefilter(&paths[])
+        } else {
+            representatives.iter().map(|_| true).collect()
+        };
+
+        for (&path_id, &needed) in representatives.iter().zip(representatives_reading.iter()) {
+            needs_reading[path_id] = needed;
+        }
+
+        group.remaining = needs_reading.iter().filter(|&&needed| needed).count();
+        if group.remaining < 2 { continue; }
+
+        for (path_id, path) in group.paths.iter().enumerate() {
+            if !needs_reading[path_id] { continue; }
+
+            let job = DigestJob {
+                id: (group_id, path_id),
+                path: path.clone(),
+            };
+
+            queues.push(job);
+        }
+
+        size_groups.insert(group_id, group);
+    }
 
     size_groups
 }
 
-fn worker(stealer: deque::Stealer<DigestJob>, tx: Sender<DigestJobResult>) {
-    let mut hasher = filehasher::new(BUFFER_SIZE);
+// Which files of the group need to be read. A file whose btrfs checksums don't
+// match those of any other member can't be a duplicate. When any member's
+// checksums are unavailable, though, it could match anything, so all are read.
+fn csum_prefilter(paths: &[Arc<Path>]) -> Vec<bool> {
+    let csums: Vec<Option<Vec<u8>>> = paths.iter().map(|path| {
+        let csums = File::open(&**path).and_then(|file| btrfs::data_csums(&file));
+
+        match csums {
+            Ok(csums) => csums,
+            Err(err)  => {
+                debug!("Couldn't get checksums for {}: {}", path.display(), err);
+                None
+            }
+        }
+    }).collect();
+
+    if csums.iter().any(|csums| csums.is_none()) {
+        return paths.iter().map(|_| true).collect();
+    }
+
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+
+    for file_csums in csums.iter() {
+        let file_csums = &file_csums.as_ref().unwrap()[];
+        *counts.entry(file_csums).get().unwrap_or_else(|entry| entry.insert(0)) += 1;
+    }
+
+    csums.iter().map(|file_csums| {
+        let file_csums = &file_csums.as_ref().unwrap()[];
+        *counts.get(file_csums).unwrap() > 1
+    }).collect()
+}
 
+fn worker(
+    stealer: deque::Stealer<DigestJob>,
+    tx: Sender<DigestJobResult>,
+    mut hasher: filehasher::FileHasher)
+{
     loop {
+        if signals::cancelled() { break; }
+
         let DigestJob { id, path } = match stealer.steal() {
             deque::Empty     => break,
             deque::Abort     => continue,
             deque::Data(job) => job,
         };
 
-        let result = match File::open(& *path) {
-            Ok(file) => {
-                let digest = hasher.hash_whole_file(file);
-                DigestResult::Successful(digest)
-            },
+        let result = match hasher.hash_whole_file(&*path) {
+            Ok(digest) => DigestResult::Successful(digest),
+            Err(err)   => DigestResult::Error(err),
+        };
 
-            Err(err) => {
-                DigestResult::Error(err)
+        tx.send(DigestJobResult { id: id, result: result }).unwrap();
+    }
+}
+
+struct InFlight {
+    id: (usize, usize),
+    file: RawFile,
+    buffer: AlignedBuffer,
+    hasher: Sha256,
+}
+
+struct UringWorker {
+    ring: uring::Ring,
+
+    // Files being read, indexed by the ring's user_data
+    slots: Vec<Option<InFlight>>,
+
+    // The iovec of the read in flight for each slot. Kept apart from the slots
+    // so that their addresses never change while the kernel holds them.
+    iovecs: Vec<IoVec>,
+
+    buffer_size: usize,
+    io_strategy: IoStrategy,
+    throttle: Arc<Throttle>,
+}
+
+impl UringWorker {
+    fn submit_read(&mut self, slot_id: usize) {
+        let in_flight = self.slots[slot_id].as_mut().unwrap();
+
+        {
+            let buffer = in_flight.buffer.as_mut_slice();
+            let iovec  = &mut self.iovecs[slot_id];
+
+            iovec.base = buffer.as_mut_ptr() as *mut c_void;
+            iovec.len  = buffer.len() as ::libc::size_t;
+        }
+
+        let iovec = &self.iovecs[slot_id] as *const IoVec;
+
+        // There is a submission entry for each slot, so the queue can't be full
+        assert!(self.ring.push_readv(in_flight.file.fd(), iovec, in_flight.file.position(), slot_id as u64));
+    }
+
+    // Feeds the result of a completed read to its file. Returns the file's
+    // digest once it has been read until the end.
+    fn complete_read(&mut self, slot_id: usize, result: i32) -> Option<DigestJobResult> {
+        let finished = {
+            let in_flight = self.slots[slot_id].as_mut().unwrap();
+
+            if result < 0 {
+                Some(DigestResult::Error(HashError::from_errno(-result)))
+            } else if result == 0 {
+                Some(DigestResult::Successful(filehasher::whole_file_digest(&mut in_flight.hasher)))
+            } else {
+                let count = result as usize;
+
+                self.throttle.acquire(count);
+                in_flight.hasher.input(&in_flight.buffer.as_slice()[..count]);
+                in_flight.file.consumed(count);
+
+                None
             }
         };
 
-        tx.send(DigestJobResult { id: id, result: result }).unwrap();
+        match finished {
+            Some(result) => {
+                let in_flight = self.slots[slot_id].take().unwrap();
+                Some(DigestJobResult { id: in_flight.id, result: result })
+            },
+
+            None => {
+                self.submit_read(slot_id);
+                None
+            }
+        }
+    }
+}
+
+fn uring_worker(stealer: deque::Stealer<DigestJob>, tx: Sender<DigestJobResult>, mut state: UringWorker) {
+    let mut exhausted = false;
+
+    loop {
+        // Whatever is in flight is still finished
+        if signals::cancelled() { exhausted = true; }
+
+        for slot_id in (0..state.slots.len()) {
+            if exhausted { break; }
+            if state.slots[slot_id].is_some() { continue; }
+
+            let DigestJob { id, path } = match stealer.steal() {
+                deque::Empty     => { exhausted = true; break; },
+                deque::Abort     => continue,
+                deque::Data(job) => job,
+            };
+
+            match RawFile::open(&*path, state.io_strategy) {
+                Ok(file) => {
+                    state.slots[slot_id] = Some(InFlight {
+                        id: id,
+                        file: file,
+                        buffer: AlignedBuffer::new(state.buffer_size),
+                        hasher: Sha256::new(),
+                    });
+
+                    state.submit_read(slot_id);
+                },
+
+                Err(err) => {
+                    tx.send(DigestJobResult { id: id, result: DigestResult::Error(err) }).unwrap();
+                }
+            }
+        }
+
+        if state.slots.iter().all(|slot| slot.is_none()) {
+            if exhausted { break; } else { continue; }
+        }
+
+        if let Err(errno) = state.ring.submit_and_wait(1) {
+            // Without the ring, nothing that's in flight can complete
+            for slot in state.slots.iter_mut() {
+                if let Some(in_flight) = slot.take() {
+                    let err = HashError::from_errno(errno);
+                    tx.send(DigestJobResult { id: in_flight.id, result: DigestResult::Error(err) }).unwrap();
+                }
+            }
+
+            return;
+        }
+
+        for (user_data, result) in state.ring.completions().into_iter() {
+            if let Some(job_result) = state.complete_read(user_data as usize, result) {
+                tx.send(job_result).unwrap();
+            }
+        }
     }
 }
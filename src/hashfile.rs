@@ -0,0 +1,350 @@
+// duperemove's `--hashfile` is a SQLite database. We only use the whole-file digests
+// on the `files` table; `hashes` (per-block digests) is created but left empty.
+//
+// Our digests are SHA256 of the whole file, one of the hash types duperemove knows.
+// Digests read from a hashfile are opaque: whatever function duperemove used, they
+// are only ever compared with each other, never with digests we compute.
+
+use rusqlite::{SqliteConnection, SqliteResult, SqliteError, SQLITE_OPEN_READ_ONLY};
+
+use std::collections::HashMap;
+use std::old_io::fs::PathExtensions;
+use std::old_io::{File, IoResult};
+
+use btrfs;
+
+// duperemove pads hash type names to 8 characters
+pub const HASH_TYPE: &'static str = "SHA256  ";
+
+const VERSION_MAJOR: i64 = 2;
+const VERSION_MINOR: i64 = 0;
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS config(keyname TEXT PRIMARY KEY NOT NULL, keyval BLOB);
+
+    CREATE TABLE IF NOT EXISTS files(
+        filename TEXT PRIMARY KEY NOT NULL,
+        ino INTEGER, subvol INTEGER, size INTEGER, blocks INTEGER,
+        mtime INTEGER, dedupe_seq INTEGER, digest BLOB,
+        UNIQUE(ino, subvol)
+    );
+
+    CREATE TABLE IF NOT EXISTS hashes(
+        digest BLOB KEY NOT NULL,
+        ino INTEGER, subvol INTEGER, loff INTEGER, flags INTEGER
+    );
+";
+
+#[derive(Clone, PartialEq, Show)]
+pub struct HashFileEntry {
+    pub path:   Path,
+    pub inode:  u64,
+    pub subvol: u64,
+    pub size:   u64,
+    pub mtime:  u64,
+    pub digest: Vec<u8>,
+}
+
+pub struct HashFile {
+    pub hash_type:  String,
+    pub block_size: u64,
+    pub entries:    Vec<HashFileEntry>,
+}
+
+impl HashFileEntry {
+    // duperemove stores the mtime in nanoseconds, old_io only gives us milliseconds
+    pub fn matches(&self, size: u64, modified_ms: u64) -> bool {
+        self.size == size && self.mtime / 1_000_000 == modified_ms
+    }
+}
+
+impl HashFile {
+    // Entries whose file is still on disk and unchanged since the hashfile was written,
+    // keyed by path
+    pub fn valid_digests(&self) -> HashMap<Path, Vec<u8>> {
+        self.entries.iter().filter(|entry| {
+            match entry.path.lstat() {
+                Ok(stat) => entry.matches(stat.size, stat.modified),
+                Err(..)  => false,
+            }
+        }).map(|entry| {
+            (entry.path.clone(), entry.digest.clone())
+        }).collect()
+    }
+}
+
+pub fn entry_for(path: &Path, digest: Vec<u8>) -> IoResult<HashFileEntry> {
+    let file = try!(File::open(path));
+    let stat = try!(file.stat());
+
+    // Not being on btrfs is fine, we just can't tell subvolumes apart
+    let subvol = btrfs::subvolume_id(&file).unwrap_or(0);
+
+    Ok(HashFileEntry {
+        path:   path.clone(),
+        inode:  stat.unstable.inode,
+        subvol: subvol,
+        size:   stat.size,
+        mtime:  stat.modified * 1_000_000,
+        digest: digest,
+    })
+}
+
+pub fn read(path: &Path) -> SqliteResult<HashFile> {
+    let conn = try!(SqliteConnection::open_with_flags(
+        &path_str(path)[],
+        SQLITE_OPEN_READ_ONLY
+    ));
+
+    let major = try!(config_value(&conn, "version_major"));
+    let major: i64 = major.parse().unwrap_or(0);
+
+    if major != VERSION_MAJOR {
+        return Err(SqliteError {
+            code: 0,
+            message: format!("Unsupported hashfile version {} (expected {})", major, VERSION_MAJOR),
+        });
+    }
+
+    let hash_type  = try!(config_value(&conn, "hash_type"));
+    let block_size = try!(config_value(&conn, "block_size"));
+
+    let mut stmt = try!(conn.prepare(
+        "SELECT filename, ino, subvol, size, mtime, digest FROM files WHERE digest IS NOT NULL"
+    ));
+
+    let mut entries = Vec::new();
+
+    for row in try!(stmt.query(&[])) {
+        let row = try!(row);
+
+        let filename: String = row.get(0);
+        let inode:  i64 = row.get(1);
+        let subvol: i64 = row.get(2);
+        let size:   i64 = row.get(3);
+        let mtime:  i64 = row.get(4);
+
+        entries.push(HashFileEntry {
+            path:   Path::new(filename),
+            inode:  inode as u64,
+            subvol: subvol as u64,
+            size:   size as u64,
+            mtime:  mtime as u64,
+            digest: row.get(5),
+        });
+    }
+
+    Ok(HashFile {
+        hash_type:  hash_type,
+        block_size: block_size.parse().unwrap_or(0),
+        entries:    entries,
+    })
+}
+
+pub fn write(path: &Path, block_size: u64, entries: &[HashFileEntry]) -> SqliteResult<()> {
+    let conn = try!(SqliteConnection::open(&path_str(path)[]));
+    try!(conn.execute_batch(SCHEMA));
+
+    // Adding to digests of another hash function would make a mix nobody can use
+    if let Ok(hash_type) = config_value(&conn, "hash_type") {
+        if hash_type != HASH_TYPE {
+            return Err(unsupported_hash_type(&hash_type[]));
+        }
+    }
+
+    let tx = try!(conn.transaction());
+
+    for entry in entries.iter() {
+        let filename = path_str(&entry.path);
+        let blocks   = ((entry.size + block_size - 1) / block_size) as i64;
+
+        try!(conn.execute(
+            "INSERT OR REPLACE INTO files(filename, ino, subvol, size, blocks, mtime, dedupe_seq, digest)
+             VALUES (?, ?, ?, ?, ?, ?, 0, ?)",
+            &[
+                &filename,
+                &(entry.inode as i64),
+                &(entry.subvol as i64),
+                &(entry.size as i64),
+                &blocks,
+                &(entry.mtime as i64),
+                &entry.digest,
+            ]
+        ));
+    }
+
+    // Files from earlier runs that weren't hashed this time are kept
+    let num_files: i64 = try!(conn.query_row("SELECT COUNT(*) FROM files", &[], |row| row.get(0)));
+
+    let config = [
+        ("version_major",   VERSION_MAJOR.to_string()),
+        ("version_minor",   VERSION_MINOR.to_string()),
+        ("hash_type",       HASH_TYPE.to_string()),
+        ("block_size",      block_size.to_string()),
+        ("num_files",       num_files.to_string()),
+        ("num_hashes",      "0".to_string()),
+        ("dedupe_sequence", "0".to_string()),
+    ];
+
+    for &(ref key, ref value) in config.iter() {
+        try!(conn.execute(
+            "INSERT OR REPLACE INTO config(keyname, keyval) VALUES (?, ?)",
+            &[key, value]
+        ));
+    }
+
+    tx.commit()
+}
+
+fn unsupported_hash_type(hash_type: &str) -> SqliteError {
+    SqliteError {
+        code: 0,
+        message: format!("Unsupported hash type {} (expected {})", hash_type.trim(), HASH_TYPE.trim()),
+    }
+}
+
+fn config_value(conn: &SqliteConnection, key: &str) -> SqliteResult<String> {
+    conn.query_row("SELECT keyval FROM config WHERE keyname = ?", &[&key], |row| {
+        row.get(0)
+    })
+}
+
+fn path_str(path: &Path) -> String {
+    String::from_utf8_lossy(path.as_vec()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write, HashFileEntry, HASH_TYPE};
+    use rusqlite::SqliteConnection;
+    use std::old_io::TempDir;
+
+    fn entry(name: &str, inode: u64, digest: Vec<u8>) -> HashFileEntry {
+        HashFileEntry {
+            path:   Path::new(format!("/some/dir/{}", name)),
+            inode:  inode,
+            subvol: 5,
+            size:   8192,
+            mtime:  1_420_070_400_000 * 1_000_000,
+            digest: digest,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tempdir  = TempDir::new("hashfile").unwrap();
+        let hashfile = tempdir.path().join("hashes.db");
+
+        let entries = vec![
+            entry("a", 257, vec![1, 2, 3, 4]),
+            entry("b", 258, vec![1, 2, 3, 4]),
+            entry("c", 259, vec![5, 6, 7, 8]),
+        ];
+
+        write(&hashfile, 4096, &entries[]).unwrap();
+        let result = read(&hashfile).unwrap();
+
+        assert_eq!(result.hash_type, HASH_TYPE);
+        assert_eq!(result.block_size, 4096);
+
+        let mut read_entries = result.entries;
+        read_entries.sort_by(|a, b| a.inode.cmp(&b.inode));
+
+        assert_eq!(read_entries, entries);
+    }
+
+    #[test]
+    fn test_rewrite_replaces_entries() {
+        let tempdir  = TempDir::new("hashfile").unwrap();
+        let hashfile = tempdir.path().join("hashes.db");
+
+        write(&hashfile, 4096, &[entry("a", 257, vec![1])]).unwrap();
+        write(&hashfile, 4096, &[entry("a", 257, vec![2])]).unwrap();
+
+        let result = read(&hashfile).unwrap();
+        assert_eq!(result.entries, vec![entry("a", 257, vec![2])]);
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let tempdir  = TempDir::new("hashfile").unwrap();
+        let hashfile = tempdir.path().join("hashes.db");
+
+        write(&hashfile, 4096, &[]).unwrap();
+
+        {
+            let path = hashfile.as_str().unwrap();
+            let conn = SqliteConnection::open(path).unwrap();
+            conn.execute("UPDATE config SET keyval = '99' WHERE keyname = 'version_major'", &[]).unwrap();
+        }
+
+        assert!(read(&hashfile).is_err());
+    }
+
+    // Laid out the way duperemove writes it, with its own hash type and digest length
+    #[test]
+    fn test_reads_other_hash_types() {
+        let tempdir  = TempDir::new("hashfile").unwrap();
+        let hashfile = tempdir.path().join("hashes.db");
+
+        {
+            let conn = SqliteConnection::open(hashfile.as_str().unwrap()).unwrap();
+
+            conn.execute_batch("
+                CREATE TABLE config(keyname TEXT PRIMARY KEY NOT NULL, keyval BLOB);
+                CREATE TABLE files(
+                    filename TEXT PRIMARY KEY NOT NULL,
+                    ino INTEGER, subvol INTEGER, size INTEGER, blocks INTEGER,
+                    mtime INTEGER, dedupe_seq INTEGER, digest BLOB,
+                    UNIQUE(ino, subvol)
+                );
+                CREATE TABLE hashes(
+                    digest BLOB KEY NOT NULL,
+                    ino INTEGER, subvol INTEGER, loff INTEGER, flags INTEGER
+                );
+
+                INSERT INTO config VALUES ('version_major', '2');
+                INSERT INTO config VALUES ('version_minor', '0');
+                INSERT INTO config VALUES ('hash_type', 'Murmur3 ');
+                INSERT INTO config VALUES ('block_size', '131072');
+                INSERT INTO config VALUES ('num_files', '1');
+                INSERT INTO config VALUES ('num_hashes', '2');
+                INSERT INTO config VALUES ('dedupe_sequence', '0');
+
+                INSERT INTO files VALUES ('/some/dir/a', 257, 5, 262144, 2,
+                    1420070400000000000, 0, x'00112233445566778899aabbccddeeff');
+                INSERT INTO hashes VALUES (x'00112233445566778899aabbccddeeff', 257, 5, 0, 0);
+                INSERT INTO hashes VALUES (x'00112233445566778899aabbccddeeff', 257, 5, 131072, 0);
+            ").unwrap();
+        }
+
+        let result = read(&hashfile).unwrap();
+
+        assert_eq!(&result.hash_type[], "Murmur3 ");
+        assert_eq!(result.block_size, 131072);
+        assert_eq!(result.entries.len(), 1);
+        assert_eq!(result.entries[0].path, Path::new("/some/dir/a"));
+        assert_eq!(result.entries[0].size, 262144);
+        assert_eq!(result.entries[0].digest, vec![
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+            0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+        ]);
+
+        // Adding our digests to it would make a mix neither side can use
+        assert!(write(&hashfile, 4096, &[entry("b", 258, vec![2])]).is_err());
+    }
+
+    #[test]
+    fn test_counts_files_from_earlier_runs() {
+        use super::config_value;
+
+        let tempdir  = TempDir::new("hashfile").unwrap();
+        let hashfile = tempdir.path().join("hashes.db");
+
+        write(&hashfile, 4096, &[entry("a", 257, vec![1])]).unwrap();
+        write(&hashfile, 4096, &[entry("b", 258, vec![2])]).unwrap();
+
+        let conn = SqliteConnection::open(hashfile.as_str().unwrap()).unwrap();
+        assert_eq!("2", &config_value(&conn, "num_files").unwrap()[]);
+    }
+}
@@ -0,0 +1,99 @@
+// Turns ranges of zeros back into holes.
+
+use fiemap::bindings::{FiemapRequest, ExtentFlags, extent_flags};
+use libc::{c_int, off_t};
+
+use std::old_io::{File, FileMode, FileAccess, IoError, IoResult, SeekStyle};
+use std::os::unix::prelude::*;
+use std::{cmp, iter};
+
+const FALLOC_FL_KEEP_SIZE:  c_int = 0x01;
+const FALLOC_FL_PUNCH_HOLE: c_int = 0x02;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+extern "C" {
+    fn fallocate(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int;
+}
+
+// Punches a hole over the parts of `[offset, offset + length)` that are backed by
+// written extents. Holes and preallocated (unwritten) extents already take no
+// space, or are meant to, and are left alone. Each part is checked to still be
+// all zeros right before punching it. Returns the number of bytes punched.
+pub fn punch_zeros(path: &Path, offset: u64, length: u64) -> IoResult<u64> {
+    let mut file = try!(File::open_mode(path, FileMode::Open, FileAccess::ReadWrite));
+    let written  = try!(written_parts(&file, offset, length));
+
+    let mut punched = 0;
+
+    for &(start, stop, _) in written.iter() {
+        if !try!(is_zeroed(&mut file, start, stop - start)) {
+            warn!("{} changed since it was read, leaving [{}..{}] alone", path.display(), start, stop);
+            continue;
+        }
+
+        let result = unsafe {
+            fallocate(file.as_raw_fd(), FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+                      start as off_t, (stop - start) as off_t)
+        };
+
+        if result < 0 {
+            return Err(IoError::last_error());
+        }
+
+        punched += stop - start;
+    }
+
+    Ok(punched)
+}
+
+// What punching `[offset, offset + length)` would free: the parts backed by written
+// extents, except those shared with another file, which stay referenced
+pub fn punchable_bytes(path: &Path, offset: u64, length: u64) -> IoResult<u64> {
+    let file    = try!(File::open(path));
+    let written = try!(written_parts(&file, offset, length));
+
+    Ok(written.iter()
+        .filter(|&&(_, _, shared)| !shared)
+        .fold(0, |total, &(start, stop, _)| total + stop - start))
+}
+
+// The parts of `[offset, offset + length)` backed by written extents, as (start,
+// stop, shared) triples
+fn written_parts(file: &File, offset: u64, length: u64) -> IoResult<Vec<(u64, u64, bool)>> {
+    let skipped: ExtentFlags = extent_flags::UNWRITTEN | extent_flags::DATA_INLINE;
+
+    let mut request = try!(FiemapRequest::new(file.as_raw_fd()));
+    let end = offset + length;
+
+    Ok(request.extents().iter()
+        .filter(|extent| !extent.flags().intersects(skipped))
+        .map(|extent| {
+            let start = cmp::max(extent.logical(), offset);
+            let stop  = cmp::min(extent.logical() + extent.length(), end);
+
+            (start, stop, extent.flags().contains(extent_flags::SHARED))
+        })
+        .filter(|&(start, stop, _)| start < stop)
+        .collect())
+}
+
+fn is_zeroed(file: &mut File, offset: u64, length: u64) -> IoResult<bool> {
+    try!(file.seek(offset as i64, SeekStyle::SeekSet));
+
+    let mut buffer: Vec<u8> = iter::repeat(0u8).take(BUFFER_SIZE).collect();
+    let mut remaining = length;
+
+    while remaining > 0 {
+        let wanted = cmp::min(remaining, BUFFER_SIZE as u64) as usize;
+        let count  = try!(file.read(&mut buffer[..wanted]));
+
+        if buffer[..count].iter().any(|&byte| byte != 0) {
+            return Ok(false);
+        }
+
+        remaining -= count as u64;
+    }
+
+    Ok(true)
+}
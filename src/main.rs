@@ -1,13 +1,17 @@
 #![crate_name = "rduperemove"]
 #![feature(plugin)]
-#![feature(io, os, collections, path)]
+#![feature(io, os, collections, path, libc, alloc, std_misc, core, thread_local)]
 
 extern crate "rustc-serialize" as rustc_serialize;
 extern crate docopt;
+extern crate rusqlite;
+extern crate time;
 
 extern crate btrfs;
+extern crate fiemap;
 extern crate crypto;
 extern crate deque;
+extern crate libc;
 
 #[macro_use]
 extern crate log;
@@ -15,35 +19,175 @@ extern crate log;
 #[plugin] #[no_link]
 extern crate docopt_macros;
 
-use std::old_io::{IoError, stdio};
-use std::os;
+use std::old_io::{File, IoError, stdio};
+use std::old_io::fs::PathExtensions;
+use std::{cmp, os};
 use std::sync::Arc;
 
+use hash_check::CheckResult;
+use chunk_check::ChunkCheckResult;
+use block_check::BlockCheckResult;
+use summary::Summary;
+use filehasher::IoStrategy;
+use hash_check::IoBackend;
+use throttle::Throttle;
+use source_policy::SourcePolicy;
+use dedup_pool::DedupGroup;
+
 mod filehasher;
 mod size_check;
 mod hash_check;
+mod hashfile;
+mod block_check;
+mod chunk_check;
+mod summary;
+mod devices;
+mod holes;
+mod signals;
+mod mapping;
+mod throttle;
+mod source_policy;
+mod plan;
+mod dedup_pool;
+
+#[allow(non_camel_case_types)]
+mod uring;
 
 const MIN_FILE_SIZE: usize = 4 * 1024;
 
+#[derive(RustcDecodable, Show, PartialEq, Copy)]
+enum Mode {
+    File,
+    Block,
+    Chunk,
+}
+
+// Whether groups are deduped right away, written to a plan, or read from one
+enum Command {
+    Dedup,
+    Plan(Path),
+    Apply(Path),
+}
+
 struct Configuration {
+    command:       Command,
     base_dirs:     Vec<Path>,
     worker_count:  usize,
+    dedup_workers: usize,
+    hdd_workers:   usize,
+    ssd_workers:   usize,
     min_file_size: usize,
+    mode:          Mode,
+    block_size:    usize,
+    chunk_size:    usize,
+    punch_holes:   bool,
+    dry_run:       bool,
+    use_csums:     bool,
+    order_by_offset: bool,
+    source_policy: SourcePolicy,
+    reference_dir: Option<Path>,
+    io_strategy:   IoStrategy,
+    buffer_size:   usize,
+    io_backend:    IoBackend,
+    queue_depth:   usize,
+    read_throttle:  Arc<Throttle>,
+    dedup_throttle: Arc<Throttle>,
+    read_hashfile:  Option<Path>,
+    write_hashfile: Option<Path>,
+    throttle_file:  Option<Path>,
 }
 
 docopt!(CommandLineOptions, "
-rduperemove - Whole-file deduplication for BTRFS filesystems on (Linux 3.13+).
+rduperemove - Whole-file and partial-file deduplication for btrfs (Linux 3.13+) and XFS \
+with reflink=1 (Linux 4.5+).
 
-Usage: rduperemove [options] <path>...
+Usage: rduperemove plan [options] <plan> <path>...
+       rduperemove apply [options] <plan>
+       rduperemove [options] <path>...
        rduperemove (-h|--help)
 
+Commands:
+    plan                                Find duplicates like a dry run, and write what would be \
+                                        deduped to <plan>, for review. File mode only.
+    apply                               Dedup what <plan> lists, skipping the files that changed \
+                                        since it was written.
+
 Options:
-    <path>...                           One or more directories (on the same btrfs filesystem) \
-                                        to deduplicate.
-    -w <count>, --worker-count <count>  Number of workers threads to use [default: 4]
+    <path>...                           One or more directories to deduplicate, on the same \
+                                        btrfs or XFS filesystem. XFS has to be made with \
+                                        reflink=1.
+    -w <count>, --worker-count <count>  Number of workers threads to use. On file mode, number \
+                                        of workers for each device of unknown kind [default: 4]
+    --dedup-workers <count>             Groups of whole files deduped at the same time. Groups \
+                                        sharing a file still wait for each other [default: 1]
+    --hdd-workers <count>               Workers reading from each spinning disk (or btrfs \
+                                        filesystem with any), on file mode [default: 1]
+    --ssd-workers <count>               Workers reading from each solid state device (or btrfs \
+                                        filesystem made only of them), on file mode [default: 16]
     -s <size>, --min-file-size <size>   Minimum file size to consider for deduplication [default: 4096]
+    -m <mode>, --mode <mode>            What to look for: identical whole files ("file"), \
+                                        runs of identical blocks across any files ("block", which \
+                                        reads each file twice) or identical content-defined \
+                                        chunks, even when shifted ("chunk") [default: file]
+    -b <size>, --block-size <size>      Block size used on block and chunk modes. Must be a \
+                                        multiple of the filesystem block size [default: 4096]
+    -c <size>, --chunk-size <size>      Average chunk size on chunk mode [default: 16384]
+    --punch-holes                       Turn runs of zero blocks into holes, instead of \
+                                        deduplicating them. Block mode only.
+    -n, --dry-run                       Find duplicates and report what would be deduped and \
+                                        roughly how much space that would free, without \
+                                        changing any file
+    --read-hashfile <file>              Use the file digests from a hashfile written by \
+                                        --write-hashfile or by duperemove, instead of reading \
+                                        files that didn't change since it was written. They're \
+                                        only used for groups where every file has one. \
+                                        Its files are also considered for deduplication. File mode only.
+    --write-hashfile <file>             Write the computed digests to a duperemove hashfile, as \
+                                        SHA256. Only files hashed on this run are added, digests \
+                                        taken from --read-hashfile aren't written again. File mode only.
+    --use-csums                         Before reading any file, compare the checksums btrfs \
+                                        keeps for its data. Files that clearly differ from all \
+                                        others aren't read at all. Requires root. File mode only.
+    --order-by-offset                   Read the files of each device in the order they're laid \
+                                        out on disk, instead of by size. Turns random reads into \
+                                        mostly sequential ones on spinning disks. File mode only.
+    --source-policy <policy>            Which file of each duplicate group the others are made \
+                                        to share the extents of: the least recently modified \
+                                        ("oldest"), the least fragmented ("contiguous"), the one \
+                                        sharing the most data already ("shared"), the one with \
+                                        the shortest path ("shortest"), or one below \
+                                        --reference-dir ("reference"). File mode only \
+                                        [default: oldest]
+    --reference-dir <dir>               Directory whose files are preferred as sources, with the \
+                                        "reference" source policy
+    --io <strategy>                     How files are read for hashing: through the page cache \
+                                        ("buffered"), bypassing it with O_DIRECT ("direct"), \
+                                        through it but evicting what was already read \
+                                        ("fadvise"), or by mapping them ("mmap"). Mapped files \
+                                        are read without mapping on the uring backend \
+                                        [default: buffered]
+    --buffer-size <size>                Read buffer size of each worker. Must be a multiple of \
+                                        4096 [default: 65536]
+    --io-backend <backend>              How hashing reads are issued: one blocking read at a time \
+                                        per worker ("threads"), or many at once through io_uring \
+                                        ("uring"), if the kernel supports it. File mode only \
+                                        [default: threads]
+    --queue-depth <count>               Files read at the same time by each worker, on the \
+                                        uring backend [default: 32]
+    --max-read-rate <bytes>             Maximum bytes per second read for hashing, across all \
+                                        workers. 0 means unlimited [default: 0]
+    --max-dedup-rate <bytes>            Maximum bytes per second submitted for deduplication. \
+                                        0 means unlimited [default: 0]
+    --throttle-file <file>              A file with "read=<bytes>" and "dedup=<bytes>" lines, \
+                                        checked every second to change the rates while running
     -h, --help                          Show this message
-", flag_min_file_size: usize, flag_worker_count: usize);
+", flag_min_file_size: usize, flag_worker_count: usize, flag_dedup_workers: usize,
+   flag_hdd_workers: usize, flag_ssd_workers: usize, flag_mode: Mode, flag_block_size: usize,
+   flag_chunk_size: usize, flag_io: IoStrategy, flag_buffer_size: usize,
+   flag_io_backend: IoBackend, flag_queue_depth: usize,
+   flag_source_policy: SourcePolicy, flag_reference_dir: Option<String>,
+   flag_max_read_rate: u64, flag_max_dedup_rate: u64, flag_throttle_file: Option<String>,
+   flag_read_hashfile: Option<String>, flag_write_hashfile: Option<String>);
 
 fn main() {
     // hacky way to set up the default logging level. See
@@ -53,29 +197,400 @@ fn main() {
         None    => os::setenv("RUST_LOG", "warn")
     };
 
-    let config     = parse_options();
-    let size_check = create_size_check(config.base_dirs, config.min_file_size);
+    let config = parse_options();
+    signals::install_handlers();
 
-    let dupes_rx = hash_check::spawn_workers(config.worker_count, size_check.size_groups());
+    if let Some(ref path) = config.throttle_file {
+        throttle::watch_control_file(path.clone(), config.read_throttle.clone(),
+                                     config.dedup_throttle.clone());
+    }
 
-    for mut paths in dupes_rx.iter() {
-        for path in paths.iter() {
-            println!("- {}", path.display());
+    let mut summary = summary::new(config.dry_run);
+
+    if let Command::Apply(ref path) = config.command {
+        apply_plan(path, &config, &mut summary);
+    } else {
+        if !can_dedup(&config.base_dirs[]) {
+            os::set_exit_status(1);
+            return;
+        }
+
+        let size_check = create_size_check(&config.base_dirs[], config.min_file_size);
+
+        match config.mode {
+            Mode::File  => dedup_files(&config, size_check, &mut summary),
+            Mode::Block => dedup_blocks(&config, size_check, &mut summary),
+            Mode::Chunk => dedup_chunks(&config, size_check, &mut summary),
+        }
+    }
+
+    summary.print();
+}
+
+// Whether every directory is on a filesystem that can dedup, complaining about
+// those that aren't
+fn can_dedup(base_dirs: &[Path]) -> bool {
+    base_dirs.iter().all(|dir| {
+        match btrfs::filesystem(dir) {
+            Ok(Some(_)) => true,
+
+            Ok(None) => {
+                error!("{} isn't on btrfs or XFS, which are the only filesystems that can \
+                        deduplicate", dir.display());
+                false
+            },
+
+            Err(err) => {
+                error!("Couldn't tell the filesystem of {}: {}", dir.display(), err);
+                false
+            }
+        }
+    })
+}
+
+fn dedup_files(config: &Configuration, mut size_check: size_check::SizeCheck, summary: &mut Summary) {
+    let precomputed = match config.read_hashfile {
+        Some(ref path) => load_hashfile(path, &mut size_check),
+        None           => hash_check::Precomputed::new(),
+    };
+
+    let options = hash_check::Options {
+        worker_count: config.worker_count,
+        hdd_workers:  config.hdd_workers,
+        ssd_workers:  config.ssd_workers,
+        io_strategy:  config.io_strategy,
+        buffer_size:  config.buffer_size,
+        io_backend:   config.io_backend,
+        queue_depth:  config.queue_depth,
+        use_csums:    config.use_csums,
+        order_by_offset: config.order_by_offset,
+        read_throttle: config.read_throttle.clone(),
+    };
+
+    let results_rx = hash_check::spawn_workers(options, size_check.size_groups(), precomputed);
+
+    let pool = dedup_pool::spawn(config.dedup_workers, config.dedup_throttle.clone());
+
+    let mut digests = Vec::new();
+    let mut plan = plan::new();
+
+    for result in results_rx.iter() {
+        let mut paths = match result {
+            CheckResult::Duplicates(paths) => paths,
+
+            CheckResult::Digest(path, digest) => {
+                if config.write_hashfile.is_some() {
+                    digests.push((path, digest));
+                }
+
+                continue;
+            },
+
+            CheckResult::Failed(path, err) => {
+                summary.add_failure(path, err);
+                continue;
+            },
+
+            CheckResult::AlreadyDeduped(paths) => {
+                for path in paths.iter() {
+                    println!("- {}", path.display());
+                }
+
+                println!("Already deduped\n");
+                summary.add_already_deduped(paths.len() - 1);
+                continue;
+            },
+        };
+
+        // Still drained, so that the digests make it to the hashfile
+        if signals::cancelled() { continue; }
+
+        let source = source_policy::choose_source(config.source_policy,
+                                                  config.reference_dir.as_ref(),
+                                                  &mut paths);
+
+        drop_already_deduped(&*source, &mut paths);
+
+        if paths.is_empty() {
+            print_group(&*source, &paths[]);
+            println!("Already deduped\n");
+            continue;
+        }
+
+        if config.dry_run {
+            if let Command::Plan(_) = config.command {
+                plan.add_group(&*source, &paths[]);
+            }
+
+            let size = source.stat().map(|stat| stat.size).unwrap_or(0);
+
+            print_group(&*source, &paths[]);
+            summary.add_dedup(print_estimate(size, &paths[]));
+            continue;
+        }
+
+        let finished = pool.submit(DedupGroup { source: source, destinations: paths });
+        record_dedups(finished.into_iter(), summary);
+    }
+
+    record_dedups(pool.finish().iter(), summary);
+
+    if let Some(ref path) = config.write_hashfile {
+        write_hashfile(path, digests);
+    }
+
+    if let Command::Plan(ref path) = config.command {
+        if let Err(err) = plan::write(path, &plan) {
+            warn!("Couldn't write plan {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn apply_plan(path: &Path, config: &Configuration, summary: &mut Summary) {
+    let plan = match plan::read(path) {
+        Ok(plan) => plan,
+        Err(err) => {
+            warn!("Couldn't read plan {}: {:?}", path.display(), err);
+            return;
+        }
+    };
+
+    let pool = dedup_pool::spawn(config.dedup_workers, config.dedup_throttle.clone());
+
+    for group in plan.groups.iter() {
+        if signals::cancelled() { break; }
+
+        if let Err(reason) = group.source.verify() {
+            println!("- {} (source)", group.source.path);
+            println!("Skipped, the source changed since the plan was made\n");
+
+            summary.add_skipped(Path::new(&group.source.path[]), reason);
+            continue;
+        }
+
+        let mut destinations = Vec::new();
+
+        for destination in group.destinations.iter() {
+            match destination.verify() {
+                Ok(())      => destinations.push(destination.path()),
+                Err(reason) => summary.add_skipped(Path::new(&destination.path[]), reason),
+            }
+        }
+
+        if destinations.is_empty() {
+            println!("- {} (source)", group.source.path);
+            println!("Skipped, every destination changed since the plan was made\n");
+            continue;
+        }
+
+        if config.dry_run {
+            print_group(&*group.source.path(), &destinations[]);
+            summary.add_dedup(print_estimate(group.source.size, &destinations[]));
+            continue;
+        }
+
+        let group = DedupGroup { source: group.source.path(), destinations: destinations };
+        record_dedups(pool.submit(group).into_iter(), summary);
+    }
+
+    record_dedups(pool.finish().iter(), summary);
+}
+
+fn record_dedups<I: Iterator<Item=dedup_pool::Finished>>(finished: I, summary: &mut Summary) {
+    for (group, result) in finished {
+        print_group(&*group.source, &group.destinations[]);
+        summary.add_dedup(print_dedup_result(result));
+    }
+}
+
+fn print_group(source: &Path, destinations: &[Arc<Path>]) {
+    println!("- {} (source)", source.display());
+
+    for path in destinations.iter() {
+        println!("- {}", path.display());
+    }
+}
+
+// Drops the destinations that already share all their extents with the source,
+// such as the files hash checking folded into it. Those were already counted in
+// the summary, when hash checking reported them.
+fn drop_already_deduped(source: &Path, paths: &mut Vec<Arc<Path>>) {
+    let source_file = match File::open(source) {
+        Ok(file) => file,
+        Err(_)   => return,
+    };
+
+    paths.retain(|path| {
+        let compared = File::open(&**path).and_then(|file| fiemap::compare(&source_file, &file));
+
+        match compared {
+            Ok(fiemap::ComparisonResult::AlreadyDeduped) => false,
+            _ => true,
+        }
+    });
+}
+
+fn dedup_blocks(config: &Configuration, size_check: size_check::SizeCheck, summary: &mut Summary) {
+    let ranges_rx = block_check::spawn_workers(
+        config.worker_count,
+        size_check.all_paths(),
+        config.block_size,
+        config.io_strategy,
+        config.buffer_size,
+        config.read_throttle.clone(),
+        config.punch_holes
+    );
+
+    for result in ranges_rx.iter() {
+        match result {
+            BlockCheckResult::Failed(path, err) => summary.add_failure(path, err),
+
+            // Still drained, so that read failures make it to the summary
+            _ if signals::cancelled() => (),
+
+            BlockCheckResult::Range(range) => {
+                summary.add_dedup(dedup_range(range, config));
+            },
+
+            BlockCheckResult::Zeros(range) => {
+                summary.add_punched(punch_zeros(range, config.dry_run));
+            },
+        }
+    }
+}
+
+fn dedup_chunks(config: &Configuration, size_check: size_check::SizeCheck, summary: &mut Summary) {
+    let results_rx = chunk_check::spawn_workers(
+        config.worker_count,
+        size_check.all_paths(),
+        config.chunk_size,
+        config.block_size,
+        config.io_strategy,
+        config.buffer_size,
+        config.read_throttle.clone()
+    );
+
+    for result in results_rx.iter() {
+        match result {
+            ChunkCheckResult::Range(_) if signals::cancelled() => (),
+
+            ChunkCheckResult::Range(range) => {
+                summary.add_dedup(dedup_range(range, config));
+            },
+
+            ChunkCheckResult::Failed(path, err) => summary.add_failure(path, err),
+
+            ChunkCheckResult::Totals { matched_bytes, unaligned_bytes } => {
+                println!("Found {} bytes of duplicate data. {} of those couldn't be aligned \
+                          to the block size and were left alone", matched_bytes, unaligned_bytes);
+            },
+        }
+    }
+}
+
+fn dedup_range(range: block_check::DuplicateRange, config: &Configuration) -> usize {
+    println!("- {} [{}..{}]", range.source.display(),
+             range.source_offset, range.source_offset + range.length);
+    println!("- {} [{}..{}]", range.destination.display(),
+             range.destination_offset, range.destination_offset + range.length);
+
+    if config.dry_run {
+        // As on file mode, what's already shared stays referenced
+        let shared = File::open(&*range.destination).and_then(|file| {
+            fiemap::shared_bytes_in(&file, range.destination_offset, range.length)
+        });
+
+        let estimate = range.length - cmp::min(shared.unwrap_or(0), range.length);
+
+        println!("Would free about {} bytes\n", estimate);
+        return estimate as usize;
+    }
+
+    let destinations = vec![(range.destination, range.destination_offset)];
+    config.dedup_throttle.acquire(range.length as usize);
+
+    let dedup = btrfs::new_range_dedup(range.source, range.source_offset, range.length, destinations);
+    print_dedup_result(dedup.perform())
+}
+
+// Prints what went wrong, if anything, and returns the bytes deduped
+fn print_dedup_result(result: btrfs::DedupResult) -> usize {
+    let report = match result {
+        Ok(report) => report,
+        Err(err)   => {
+            println!("Couldn't use the source: {:?}\n", err);
+            return 0;
+        }
+    };
+
+    for destination in report.destinations.iter() {
+        if let Err(ref err) = destination.status {
+            println!("Couldn't dedup {} (after {} bytes): {:?}",
+                     destination.path.display(), destination.bytes_deduped, err);
         }
 
-        let source = paths.pop().unwrap();
+        if let Some(ref err) = destination.tail_error {
+            println!("Couldn't dedup the partial block at the end of {}: {:?}",
+                     destination.path.display(), err);
+        }
+    }
+
+    let deduped = report.bytes_deduped() as usize;
+
+    println!("Deduped {} bytes ({} calls, {}ms)\n", deduped, report.calls,
+             report.elapsed.num_milliseconds());
+    deduped
+}
+
+// What deduping `destinations` against a source of `size` bytes would free. Data
+// they already share with some other file doesn't count, as it stays referenced.
+fn print_estimate(size: u64, destinations: &[Arc<Path>]) -> usize {
+    let estimate = destinations.iter().fold(0, |total, path| {
+        let shared = File::open(&**path).and_then(|file| fiemap::shared_bytes(&file));
 
-        let dedup  = btrfs::new_dedup(source, &paths[]);
-        let deduped = dedup.perform();
+        // The last extent may go past the end of the file
+        total + size - cmp::min(shared.unwrap_or(0), size)
+    });
 
-        println!("Deduped {} bytes\n", deduped);
+    println!("Would free about {} bytes\n", estimate);
+    estimate as usize
+}
+
+fn punch_zeros(range: block_check::ZeroRange, dry_run: bool) -> u64 {
+    println!("- {} [{}..{}] is all zeros", range.path.display(),
+             range.offset, range.offset + range.length);
+
+    if dry_run {
+        return match holes::punchable_bytes(&*range.path, range.offset, range.length) {
+            Ok(estimate) => {
+                println!("Would punch a hole, freeing about {} bytes\n", estimate);
+                estimate
+            },
+
+            Err(err) => {
+                println!("Couldn't tell what punching a hole would free: {}\n", err);
+                0
+            }
+        };
+    }
+
+    match holes::punch_zeros(&*range.path, range.offset, range.length) {
+        Ok(punched) => {
+            println!("Punched {} bytes\n", punched);
+            punched
+        },
+
+        Err(err) => {
+            println!("Couldn't punch a hole: {}\n", err);
+            0
+        }
     }
 }
 
-fn create_size_check(base_dirs: Vec<Path>, min_file_size: usize) -> size_check::SizeCheck {
+fn create_size_check(base_dirs: &[Path], min_file_size: usize) -> size_check::SizeCheck {
     let mut check = size_check::new_check(min_file_size);
 
-    for base_dir in base_dirs.into_iter() {
+    for base_dir in base_dirs.iter().cloned() {
         let mut stderr = stdio::stderr();
 
         let on_err = move |err: IoError| {
@@ -88,6 +603,40 @@ fn create_size_check(base_dirs: Vec<Path>, min_file_size: usize) -> size_check::
     check
 }
 
+fn load_hashfile(path: &Path, check: &mut size_check::SizeCheck) -> hash_check::Precomputed {
+    let hash_file = match hashfile::read(path) {
+        Ok(hash_file) => hash_file,
+        Err(err) => {
+            warn!("Couldn't read hashfile {}: {}", path.display(), err.message);
+            return hash_check::Precomputed::new();
+        }
+    };
+
+    for entry in hash_file.entries.iter() {
+        if let Err(err) = check.add_file(Arc::new(entry.path.clone())) {
+            warn!("Skipping hashfile entry: {}", err);
+        }
+    }
+
+    hash_file.valid_digests()
+}
+
+fn write_hashfile(path: &Path, digests: Vec<(Arc<Path>, Vec<u8>)>) {
+    let entries: Vec<_> = digests.into_iter().filter_map(|(file_path, digest)| {
+        match hashfile::entry_for(&*file_path, digest) {
+            Ok(entry) => Some(entry),
+            Err(err)  => {
+                warn!("Leaving {} out of the hashfile: {}", file_path.display(), err);
+                None
+            }
+        }
+    }).collect();
+
+    if let Err(err) = hashfile::write(path, MIN_FILE_SIZE as u64, &entries[]) {
+        warn!("Couldn't write hashfile {}: {}", path.display(), err.message);
+    }
+}
+
 fn parse_options() -> Configuration {
     let options: CommandLineOptions = CommandLineOptions::docopt()
         .decode()
@@ -96,16 +645,217 @@ fn parse_options() -> Configuration {
     let min_file_size = if options.flag_min_file_size >= MIN_FILE_SIZE {
          options.flag_min_file_size
      } else {
-         warn!("Btrfs can't deduplicate files smaller than 4096 bytes. \
+         warn!("Files smaller than 4096 bytes can't be deduplicated. \
                 Using that instead of the passed {}", options.flag_min_file_size);
          MIN_FILE_SIZE
      };
 
+    let block_size = if options.flag_block_size > 0 && options.flag_block_size % MIN_FILE_SIZE == 0 {
+        options.flag_block_size
+    } else {
+        warn!("Block size must be a multiple of 4096 bytes. \
+               Using that instead of the passed {}", options.flag_block_size);
+        MIN_FILE_SIZE
+    };
+
+    let chunk_size = if options.flag_chunk_size >= block_size {
+        options.flag_chunk_size
+    } else {
+        warn!("Chunks smaller than a block can never be deduplicated. \
+               Using {} instead of the passed {}", block_size, options.flag_chunk_size);
+        block_size
+    };
+
+    let buffer_size = if options.flag_buffer_size > 0 && options.flag_buffer_size % MIN_FILE_SIZE == 0 {
+        options.flag_buffer_size
+    } else {
+        warn!("Buffer size must be a multiple of 4096 bytes. \
+               Using 65536 instead of the passed {}", options.flag_buffer_size);
+        64 * 1024
+    };
+
+    let rate = |rate: u64| if rate > 0 { Some(rate) } else { None };
+
+    let command = if options.cmd_plan {
+        Command::Plan(Path::new(options.arg_plan))
+    } else if options.cmd_apply {
+        Command::Apply(Path::new(options.arg_plan))
+    } else {
+        Command::Dedup
+    };
+
+    let mode = if options.cmd_plan && options.flag_mode != Mode::File {
+        warn!("Plans can only be made on file mode. Using that instead of the passed {:?}",
+              options.flag_mode);
+        Mode::File
+    } else {
+        options.flag_mode
+    };
+
     let base_dirs = options.arg_path.into_iter().map(|base_dir| Path::new(base_dir)).collect();
 
     Configuration {
+        command: command,
         worker_count: options.flag_worker_count,
+        dedup_workers: cmp::max(options.flag_dedup_workers, 1),
+        hdd_workers: cmp::max(options.flag_hdd_workers, 1),
+        ssd_workers: cmp::max(options.flag_ssd_workers, 1),
         min_file_size: min_file_size,
         base_dirs: base_dirs,
+        mode: mode,
+        block_size: block_size,
+        chunk_size: chunk_size,
+        punch_holes: options.flag_punch_holes,
+        dry_run: options.flag_dry_run || options.cmd_plan,
+        use_csums: options.flag_use_csums,
+        order_by_offset: options.flag_order_by_offset,
+        source_policy: options.flag_source_policy,
+        reference_dir: options.flag_reference_dir.map(|path| Path::new(path)),
+        io_strategy: options.flag_io,
+        buffer_size: buffer_size,
+        io_backend: options.flag_io_backend,
+        queue_depth: cmp::max(options.flag_queue_depth, 1),
+        read_throttle:  Arc::new(Throttle::new(rate(options.flag_max_read_rate))),
+        dedup_throttle: Arc::new(Throttle::new(rate(options.flag_max_dedup_rate))),
+        read_hashfile:  options.flag_read_hashfile.map(|path| Path::new(path)),
+        write_hashfile: options.flag_write_hashfile.map(|path| Path::new(path)),
+        throttle_file:  options.flag_throttle_file.map(|path| Path::new(path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_plan, Command, Configuration, Mode};
+    use filehasher::IoStrategy;
+    use hash_check::IoBackend;
+    use source_policy::SourcePolicy;
+    use throttle::Throttle;
+    use plan::{self, FileIdentity};
+    use summary;
+
+    use std::old_io::{File, FileAccess, FileMode, TempDir};
+    use std::old_io::fs;
+    use std::sync::Arc;
+
+    fn configuration(command: Command, dry_run: bool) -> Configuration {
+        Configuration {
+            command:       command,
+            base_dirs:     Vec::new(),
+            worker_count:  1,
+            dedup_workers: 1,
+            hdd_workers:   1,
+            ssd_workers:   1,
+            min_file_size: 4096,
+            mode:          Mode::File,
+            block_size:    4096,
+            chunk_size:    16384,
+            punch_holes:   false,
+            dry_run:       dry_run,
+            use_csums:     false,
+            order_by_offset: false,
+            source_policy: SourcePolicy::Oldest,
+            reference_dir: None,
+            io_strategy:   IoStrategy::Buffered,
+            buffer_size:   65536,
+            io_backend:    IoBackend::Threads,
+            queue_depth:   1,
+            read_throttle:  Arc::new(Throttle::new(None)),
+            dedup_throttle: Arc::new(Throttle::new(None)),
+            read_hashfile:  None,
+            write_hashfile: None,
+            throttle_file:  None,
+        }
+    }
+
+    // Two files with the same data, and a plan to dedup the second into the first
+    fn planned(tempdir: &TempDir, contents: &[u8]) -> (Vec<Path>, Path) {
+        let paths: Vec<Path> = ["a", "b"].iter().map(|name| tempdir.path().join(*name)).collect();
+
+        for path in paths.iter() {
+            File::create(path).write_all(contents).unwrap();
+        }
+
+        let plan_path = tempdir.path().join("plan.json");
+        let mut dedup_plan = plan::new();
+        dedup_plan.add_group(&paths[0], &[Arc::new(paths[1].clone())]);
+        plan::write(&plan_path, &dedup_plan).unwrap();
+
+        (paths, plan_path)
+    }
+
+    fn apply(plan_path: &Path, dry_run: bool) -> summary::Summary {
+        let mut summary = summary::new(dry_run);
+        apply_plan(plan_path, &configuration(Command::Apply(plan_path.clone()), dry_run), &mut summary);
+
+        summary
+    }
+
+    #[test]
+    fn test_dry_run_apply_leaves_files_untouched() {
+        let tempdir = TempDir::new("apply").unwrap();
+        let contents = [7u8; 8192];
+
+        let (paths, plan_path) = planned(&tempdir, &contents[]);
+
+        let identities: Vec<FileIdentity> = paths.iter().map(|path| {
+            FileIdentity::of(path).unwrap()
+        }).collect();
+
+        let summary = apply(&plan_path, true);
+
+        // Counted as planned, but the files are the same ones, with the same data
+        assert_eq!(1, summary.groups);
+        assert!(summary.skipped.is_empty());
+
+        for (path, identity) in paths.iter().zip(identities.iter()) {
+            assert_eq!(*identity, FileIdentity::of(path).unwrap());
+            assert_eq!(&contents[], &File::open(path).read_to_end().unwrap()[]);
+            assert!(identity.verify().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_apply_skips_files_whose_size_changed() {
+        let tempdir = TempDir::new("apply").unwrap();
+        let (paths, plan_path) = planned(&tempdir, &[7u8; 8192]);
+
+        let mut file = File::open_mode(&paths[1], FileMode::Append, FileAccess::Write).unwrap();
+        file.write_all(&[7u8]).unwrap();
+        drop(file);
+
+        let summary = apply(&plan_path, false);
+
+        assert_eq!(0, summary.groups);
+        assert_eq!(summary.skipped, vec![(paths[1].clone(), "modified".to_string())]);
+    }
+
+    #[test]
+    fn test_apply_skips_files_whose_mtime_changed() {
+        let tempdir = TempDir::new("apply").unwrap();
+        let (paths, plan_path) = planned(&tempdir, &[7u8; 8192]);
+
+        fs::change_file_times(&paths[1], 1000, 1000).unwrap();
+
+        let summary = apply(&plan_path, false);
+
+        assert_eq!(0, summary.groups);
+        assert_eq!(summary.skipped, vec![(paths[1].clone(), "modified".to_string())]);
+    }
+
+    // Same path, size and data, but a new inode
+    #[test]
+    fn test_apply_skips_files_replaced_by_others() {
+        let tempdir = TempDir::new("apply").unwrap();
+        let contents = [7u8; 8192];
+        let (paths, plan_path) = planned(&tempdir, &contents[]);
+
+        let replacement = tempdir.path().join("replacement");
+        File::create(&replacement).write_all(&contents[]).unwrap();
+        fs::rename(&replacement, &paths[1]).unwrap();
+
+        let summary = apply(&plan_path, false);
+
+        assert_eq!(0, summary.groups);
+        assert_eq!(summary.skipped, vec![(paths[1].clone(), "replaced by another file".to_string())]);
     }
 }
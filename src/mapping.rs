@@ -0,0 +1,245 @@
+// Read-only file mappings, for hashing files without copying them into a buffer.
+//
+// Touching a page past the end of a file that shrank after being mapped raises
+// SIGBUS. The handler below maps a page of zeros over the faulting one and flags
+// the current thread, so that the read carries on and the hash is thrown away.
+// It's only installed while there are mappings, and any other SIGBUS is passed on
+// to whatever handled it before.
+
+use filehasher::{HashError, HashResult};
+use libc::{self, c_int, c_void, size_t};
+
+use std::ffi::CString;
+use std::old_io::{self, IoError};
+use std::sync::{StaticMutex, MUTEX_INIT};
+use std::{mem, ptr, raw};
+
+const SIGBUS: c_int = 7;
+const SA_SIGINFO: c_int = 4;
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const MADV_SEQUENTIAL: c_int = 2;
+
+const EIO: i32 = 5;
+
+const PAGE_SIZE: usize = 4096;
+
+// Whether this thread is reading a mapping, and whether that read hit a page
+// that's no longer backed by the file
+#[thread_local]
+static mut READING_MAPPING: bool = false;
+#[thread_local]
+static mut MAPPING_FAULTED: bool = false;
+
+// Guards the two below
+static HANDLER_LOCK: StaticMutex = MUTEX_INIT;
+
+// Mappings alive, and the action SIGBUS had before the first of them
+static mut LIVE_MAPPINGS: usize = 0;
+static mut PREVIOUS_ACTION: sigaction = sigaction {
+    sa_sigaction: SIG_DFL,
+    sa_mask:      [0; 16],
+    sa_flags:     0,
+    sa_restorer:  0,
+};
+
+// glibc's layout, on 64 bits
+#[repr(C)]
+struct sigaction {
+    sa_sigaction: usize,
+    sa_mask:      [u64; 16],
+    sa_flags:     c_int,
+    sa_restorer:  usize,
+}
+
+// Only the leading fields (si_signo, si_errno, si_code, padding) and si_addr
+#[repr(C)]
+struct siginfo {
+    header:  [c_int; 4],
+    si_addr: *mut c_void,
+}
+
+extern "C" {
+    #[link_name = "sigaction"]
+    fn set_sigaction(signum: c_int, action: *const sigaction, old: *mut sigaction) -> c_int;
+    fn madvise(addr: *mut c_void, length: size_t, advice: c_int) -> c_int;
+}
+
+extern "C" fn handle_sigbus(signum: c_int, info: *mut siginfo, context: *mut c_void) {
+    unsafe {
+        let page = ((*info).si_addr as usize & !(PAGE_SIZE - 1)) as *mut c_void;
+
+        let replaced = READING_MAPPING && libc::mmap(
+            page,
+            PAGE_SIZE as size_t,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+            -1,
+            0
+        ) != libc::MAP_FAILED;
+
+        if replaced {
+            MAPPING_FAULTED = true;
+        } else {
+            forward_sigbus(signum, info, context);
+        }
+    }
+}
+
+// Hands a fault that isn't ours to the previous action
+unsafe fn forward_sigbus(signum: c_int, info: *mut siginfo, context: *mut c_void) {
+    let previous = &PREVIOUS_ACTION;
+
+    match previous.sa_sigaction {
+        // Let the fault happen again, with the previous action in place
+        SIG_DFL | SIG_IGN => { set_sigaction(SIGBUS, previous, ptr::null_mut()); },
+
+        handler if previous.sa_flags & SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(c_int, *mut siginfo, *mut c_void) = mem::transmute(handler);
+            handler(signum, info, context);
+        },
+
+        handler => {
+            let handler: extern "C" fn(c_int) = mem::transmute(handler);
+            handler(signum);
+        },
+    }
+}
+
+// Keeps the handler installed while alive. The first one installs it, and the
+// last one to go puts back the previous action.
+struct HandlerUse;
+
+impl HandlerUse {
+    fn new() -> HandlerUse {
+        let _guard = HANDLER_LOCK.lock().unwrap();
+
+        unsafe {
+            if LIVE_MAPPINGS == 0 {
+                let action = sigaction {
+                    sa_sigaction: handle_sigbus as usize,
+                    sa_mask:      [0; 16],
+                    sa_flags:     SA_SIGINFO,
+                    sa_restorer:  0,
+                };
+
+                set_sigaction(SIGBUS, &action, &mut PREVIOUS_ACTION);
+            }
+
+            LIVE_MAPPINGS += 1;
+        }
+
+        HandlerUse
+    }
+}
+
+impl Drop for HandlerUse {
+    fn drop(&mut self) {
+        let _guard = HANDLER_LOCK.lock().unwrap();
+
+        unsafe {
+            LIVE_MAPPINGS -= 1;
+
+            if LIVE_MAPPINGS == 0 {
+                set_sigaction(SIGBUS, &PREVIOUS_ACTION, ptr::null_mut());
+            }
+        }
+    }
+}
+
+pub struct Mapping {
+    address: *mut c_void,
+    len: usize,
+    _handler: HandlerUse,
+}
+
+impl Mapping {
+    pub fn open(path: &Path) -> HashResult<Mapping> {
+        let handler = HandlerUse::new();
+
+        let c_path = CString::from_slice(path.as_vec());
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY, 0) };
+
+        if fd < 0 {
+            return Err(HashError::last());
+        }
+
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            let err = HashError::last();
+            unsafe { libc::close(fd); }
+
+            return Err(err);
+        }
+
+        let len = stat.st_size as usize;
+
+        // mmap refuses empty mappings
+        if len == 0 {
+            unsafe { libc::close(fd); }
+            return Ok(Mapping { address: ptr::null_mut(), len: 0, _handler: handler });
+        }
+
+        let address = unsafe {
+            libc::mmap(ptr::null_mut(), len as size_t, libc::PROT_READ, libc::MAP_PRIVATE, fd, 0)
+        };
+
+        let result = if address == libc::MAP_FAILED {
+            Err(HashError::last())
+        } else {
+            // Only advice, failing is harmless
+            unsafe { madvise(address, len as size_t, MADV_SEQUENTIAL); }
+
+            Ok(Mapping { address: address, len: len, _handler: handler })
+        };
+
+        // The mapping holds its own reference to the file
+        unsafe { libc::close(fd); }
+
+        result
+    }
+
+    // Calls `f` with consecutive pieces of the file, of at most `piece_size`
+    // bytes. Fails if the file shrank in the meantime.
+    pub fn for_each_piece<F: FnMut(&[u8])>(&self, piece_size: usize, mut f: F) -> HashResult<()> {
+        let contents: &[u8] = unsafe {
+            mem::transmute(raw::Slice { data: self.address as *const u8, len: self.len })
+        };
+
+        unsafe {
+            READING_MAPPING = true;
+            MAPPING_FAULTED = false;
+        }
+
+        for piece in contents.chunks(piece_size) {
+            f(piece);
+        }
+
+        let faulted = unsafe {
+            READING_MAPPING = false;
+            MAPPING_FAULTED
+        };
+
+        if faulted {
+            let error = IoError {
+                kind: old_io::OtherIoError,
+                desc: "File shrank while being read",
+                detail: None,
+            };
+
+            return Err(HashError { errno: EIO, error: error });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { libc::munmap(self.address, self.len as size_t); }
+        }
+    }
+}
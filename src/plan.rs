@@ -0,0 +1,159 @@
+// Dedup plans: the groups a run would dedup, written down as JSON to be reviewed
+// and applied later. Each file is recorded with enough to tell whether it was
+// changed or replaced in between, in which case applying leaves it alone.
+
+use btrfs;
+use rustc_serialize::json;
+
+use std::old_io::{self, File, IoError, IoResult};
+use std::sync::Arc;
+
+// Bumped on incompatible changes to the format
+pub const VERSION: u32 = 1;
+
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Show)]
+pub struct FileIdentity {
+    pub path:       String,
+    pub inode:      u64,
+    pub size:       u64,
+    pub mtime:      u64,
+    pub generation: u64,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Show)]
+pub struct PlanGroup {
+    pub source:       FileIdentity,
+    pub destinations: Vec<FileIdentity>,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Show)]
+pub struct Plan {
+    pub version: u32,
+    pub groups:  Vec<PlanGroup>,
+}
+
+#[derive(Show)]
+pub enum PlanError {
+    Io(IoError),
+    Malformed(json::DecoderError),
+    UnsupportedVersion(u32),
+}
+
+pub fn new() -> Plan {
+    Plan { version: VERSION, groups: Vec::new() }
+}
+
+impl FileIdentity {
+    pub fn of(path: &Path) -> IoResult<FileIdentity> {
+        let utf8_path = try!(path.as_str().ok_or(IoError {
+            kind: old_io::InvalidInput,
+            desc: "Path isn't valid UTF-8",
+            detail: Some(format!("{}", path.display())),
+        }));
+
+        let file = try!(File::open(path));
+        let stat = try!(file.stat());
+
+        Ok(FileIdentity {
+            path:       utf8_path.to_string(),
+            inode:      stat.unstable.inode,
+            size:       stat.size,
+            mtime:      stat.modified,
+            // Filesystems without inode generations only get the other checks
+            generation: btrfs::generation(&file).unwrap_or(0),
+        })
+    }
+
+    pub fn path(&self) -> Arc<Path> {
+        Arc::new(Path::new(&self.path[]))
+    }
+
+    // Why the file on disk isn't the one recorded anymore, if it isn't
+    pub fn verify(&self) -> Result<(), String> {
+        let current = match FileIdentity::of(&*self.path()) {
+            Ok(current) => current,
+            Err(err)    => return Err(format!("{}", err)),
+        };
+
+        if current.inode != self.inode || current.generation != self.generation {
+            Err("replaced by another file".to_string())
+        } else if current.size != self.size || current.mtime != self.mtime {
+            Err("modified".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Plan {
+    // Files whose identity can't be read are left out, with a warning. So is the
+    // whole group, if that leaves nothing to dedup.
+    pub fn add_group(&mut self, source: &Path, destinations: &[Arc<Path>]) {
+        let source = match FileIdentity::of(source) {
+            Ok(identity) => identity,
+            Err(err)     => {
+                warn!("Leaving {} out of the plan: {}", source.display(), err);
+                return;
+            }
+        };
+
+        let destinations: Vec<FileIdentity> = destinations.iter().filter_map(|path| {
+            match FileIdentity::of(&**path) {
+                Ok(identity) => Some(identity),
+                Err(err)     => {
+                    warn!("Leaving {} out of the plan: {}", path.display(), err);
+                    None
+                }
+            }
+        }).collect();
+
+        if destinations.is_empty() { return; }
+
+        self.groups.push(PlanGroup { source: source, destinations: destinations });
+    }
+}
+
+pub fn write(path: &Path, plan: &Plan) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    try!(file.write_str(&format!("{}", json::as_pretty_json(plan))[]));
+    file.write_str("\n")
+}
+
+pub fn read(path: &Path) -> Result<Plan, PlanError> {
+    let contents = try!(File::open(path).read_to_string().map_err(PlanError::Io));
+    let plan: Plan = try!(json::decode(&contents[]).map_err(PlanError::Malformed));
+
+    if plan.version != VERSION {
+        return Err(PlanError::UnsupportedVersion(plan.version));
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileIdentity, Plan, PlanGroup, VERSION};
+    use rustc_serialize::json;
+
+    fn identity(path: &str, inode: u64) -> FileIdentity {
+        FileIdentity { path: path.to_string(), inode: inode, size: 8192, mtime: 1000, generation: 7 }
+    }
+
+    #[test]
+    fn test_plans_survive_a_round_trip() {
+        let plan = Plan {
+            version: VERSION,
+            groups: vec![PlanGroup {
+                source: identity("/a", 1),
+                destinations: vec![identity("/b", 2), identity("/c", 3)],
+            }],
+        };
+
+        let encoded = format!("{}", json::as_pretty_json(&plan));
+        let decoded: Plan = json::decode(&encoded[]).unwrap();
+
+        assert_eq!(VERSION, decoded.version);
+        assert_eq!(plan.groups[0].source, decoded.groups[0].source);
+        assert_eq!(plan.groups[0].destinations, decoded.groups[0].destinations);
+    }
+}
@@ -0,0 +1,39 @@
+// Cancellation on SIGINT/SIGTERM. The first signal asks everything to wind down:
+// no new files are read and no new dedups are started, but the ones in progress
+// finish, and the summary and hashfile are still written. A second signal exits
+// right away.
+
+use libc::c_int;
+
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+const SIGINT:  c_int = 2;
+const SIGTERM: c_int = 15;
+
+// Conventional status for a process killed by SIGINT
+const INTERRUPTED_STATUS: c_int = 128 + SIGINT;
+
+static SIGNALS_RECEIVED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn _exit(status: c_int) -> !;
+}
+
+extern "C" fn handle_signal(_: c_int) {
+    // Only async-signal-safe things can be done here
+    if SIGNALS_RECEIVED.fetch_add(1, Ordering::SeqCst) > 0 {
+        unsafe { _exit(INTERRUPTED_STATUS); }
+    }
+}
+
+pub fn install_handlers() {
+    unsafe {
+        signal(SIGINT, handle_signal);
+        signal(SIGTERM, handle_signal);
+    }
+}
+
+pub fn cancelled() -> bool {
+    SIGNALS_RECEIVED.load(Ordering::SeqCst) > 0
+}
@@ -1,10 +1,12 @@
-use std::collections::{HashMap, HashSet, BinaryHeap};
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 
 use std::sync::Arc;
-use std::old_io::{FileType, IoResult, IoError, FileStat};
+use std::old_io::{File, FileType, IoResult, IoError, FileStat};
 use std::old_io::fs::PathExtensions;
-use std::old_io;
+use std::{cmp, old_io, vec};
+
+use fiemap;
 
 pub struct SizeCheck {
     min_size: usize,
@@ -20,78 +22,125 @@ impl SizeCheck {
     pub fn add_base_dir<F: FnMut(IoError)>(&mut self, dir: Arc<Path>, mut on_err: F) -> IoResult<()> {
         for file in try!(recurse_directory(&dir)) {
             match file {
-                Ok(stated_path) => {
-                    let size = stated_path.stat.size as usize;
-
-                    if size < self.min_size { continue; }
-
-                    match self.groups.entry(size) {
-                        Entry::Vacant(entry) => {
-                            entry.insert(vec!(stated_path));
-                        },
+                Ok(stated_path) => self.insert(stated_path),
+                Err(err)        => on_err(err),
+            }
+        }
 
-                        Entry::Occupied(entry) => {
-                            entry.into_mut().push(stated_path);
-                        },
-                    };
-                },
+        Ok(())
+    }
 
-                Err(err) => {
-                    on_err(err);
-                }
+    #[must_use]
+    pub fn add_file(&mut self, path: Arc<Path>) -> IoResult<()> {
+        let stat = try!(path.lstat());
+
+        match stat.kind {
+            FileType::RegularFile => {
+                self.insert(StatedPath { path: path, stat: stat });
+                Ok(())
+            },
+
+            _ => {
+                Err(IoError {
+                    kind: old_io::MismatchedFileTypeForOperation,
+                    desc: "Not a regular file!",
+                    detail: Some(format!("{}", path.display())),
+                })
             }
         }
+    }
 
-        Ok(())
+    fn insert(&mut self, stated_path: StatedPath) {
+        let size = stated_path.stat.size as usize;
+
+        if size < self.min_size { return; }
+
+        match self.groups.entry(size) {
+            Entry::Vacant(entry) => {
+                entry.insert(vec!(stated_path));
+            },
+
+            Entry::Occupied(entry) => {
+                entry.into_mut().push(stated_path);
+            },
+        };
     }
 
+    // Every file found, regardless of size matches (but still respecting the minimum size)
+    pub fn all_paths(self) -> Vec<Arc<Path>> {
+        let stated_paths = self.groups.into_iter().flat_map(|(_, stated_paths)| {
+            stated_paths.into_iter()
+        }).collect();
+
+        remove_repeated_inodes(stated_paths).into_iter().map(|stated_path| {
+            stated_path.path
+        }).collect()
+    }
+
+    // Groups of files with the same size, the ones that could free the most space
+    // first (larger ones first, on ties)
     pub fn size_groups(self) -> SizeGroups {
-        let sizes = self.groups.keys()
-            .map(|n| *n)
-            .collect::<BinaryHeap<usize>>()
-            .into_sorted_vec();
-
-        SizeGroups {
-            sorted_sizes_iter: sizes,
-            size_groups: self.groups,
-        }
+        let mut groups: Vec<(u64, usize, Vec<Arc<Path>>)> = self.groups.into_iter()
+            .filter_map(|(size, stated_paths)| {
+                let unique_stated_paths = remove_repeated_inodes(stated_paths);
+
+                if unique_stated_paths.len() < 2 { return None; }
+
+                let unique_paths: Vec<Arc<Path>> = unique_stated_paths.into_iter()
+                    .map(|stated_path| stated_path.path)
+                    .collect();
+
+                Some((potential_savings(size as u64, &unique_paths[]), size, unique_paths))
+            })
+            .collect();
+
+        groups.sort_by(|&(savings_a, size_a, _), &(savings_b, size_b, _)| {
+            (savings_b, size_b).cmp(&(savings_a, size_a))
+        });
+
+        SizeGroups { groups: groups.into_iter() }
     }
 }
 
 pub struct SizeGroups {
-    sorted_sizes_iter: Vec<usize>,
-    size_groups: HashMap<usize, Vec<StatedPath>>
+    groups: vec::IntoIter<(u64, usize, Vec<Arc<Path>>)>,
 }
 
 impl Iterator for SizeGroups {
     type Item = Vec<Arc<Path>>;
 
     fn next(&mut self) -> Option<Vec<Arc<Path>>> {
-        for size in self.sorted_sizes_iter.drain().rev() {
-            let stated_paths = self.size_groups.remove(&size).unwrap();
-            let unique_stated_paths = remove_repeated_inodes(stated_paths);
+        self.groups.next().map(|(_, _, paths)| paths)
+    }
+}
 
-            if unique_stated_paths.len() < 2 { continue; }
+// The most a group could free: all members but one, less what they already share.
+// One member's shared bytes are left out, as a copy of those has to stay. Sharing
+// with files outside of the group also counts, so this can fall short.
+fn potential_savings(size: u64, paths: &[Arc<Path>]) -> u64 {
+    let shared: Vec<u64> = paths.iter().map(|path| {
+        let shared = File::open(&**path).and_then(|file| fiemap::shared_bytes(&file));
 
-            let unique_paths = unique_stated_paths.into_iter().map(|stated_path| {
-                stated_path.path
-            }).collect();
+        // The last extent may go past the end of the file
+        cmp::min(shared.unwrap_or(0), size)
+    }).collect();
 
-            return Some(unique_paths);
-        }
+    let total_shared = shared.iter().fold(0, |total, &bytes| total + bytes);
+    let kept_shared  = shared.iter().max().map(|&bytes| bytes).unwrap_or(0);
 
-        None
-    }
+    (size * (paths.len() as u64 - 1)).saturating_sub(total_shared - kept_shared)
 }
 
 fn remove_repeated_inodes(mut stated_paths: Vec<StatedPath>) -> Vec<StatedPath> {
     let mut found = HashSet::with_capacity(stated_paths.len());
 
     stated_paths.retain(|path| {
-        let inode = path.stat.unstable.inode;
+        // Inode numbers are only unique within a filesystem, and btrfs subvolumes
+        // get a device number of their own for that reason
+        let id = (path.stat.unstable.device, path.stat.unstable.inode);
 
         // insert returns false if the value was already on the set
-        found.insert(inode)
+        found.insert(id)
     });
 
     stated_paths
@@ -0,0 +1,126 @@
+// Which member of a duplicate group is kept as the source, with all the others
+// made to share its extents.
+
+use fiemap;
+
+use std::old_io::File;
+use std::old_io::fs::PathExtensions;
+use std::sync::Arc;
+use std::u64;
+
+#[derive(RustcDecodable, Show, PartialEq, Copy)]
+pub enum SourcePolicy {
+    // The least recently modified file
+    Oldest,
+
+    // The file with the fewest extents, i.e. the least fragmented
+    Contiguous,
+
+    // The file with the most bytes already shared with other files
+    Shared,
+
+    // The file with the shortest path
+    Shortest,
+
+    // A file below the reference directory, or the oldest one if there's none
+    Reference,
+}
+
+// Removes the chosen source from `paths` and returns it. Ties are broken by age,
+// then by path, so that the same group always gets the same source.
+pub fn choose_source(policy: SourcePolicy, reference_dir: Option<&Path>, paths: &mut Vec<Arc<Path>>) -> Arc<Path> {
+    let index = {
+        let keys: Vec<(u64, u64, &[u8])> = paths.iter().map(|path| {
+            let primary = match policy {
+                SourcePolicy::Oldest     => 0,
+                SourcePolicy::Contiguous => extent_count(&**path),
+                SourcePolicy::Shared     => u64::MAX - shared_bytes(&**path),
+                SourcePolicy::Shortest   => path.as_vec().len() as u64,
+
+                SourcePolicy::Reference  => {
+                    match reference_dir {
+                        Some(dir) if dir.is_ancestor_of(&**path) => 0,
+                        _ => 1,
+                    }
+                },
+            };
+
+            (primary, modified(&**path), path.as_vec())
+        }).collect();
+
+        let (index, _) = keys.iter().enumerate().min_by(|&(_, key)| key).unwrap();
+        index
+    };
+
+    // The order of the rest doesn't matter
+    paths.swap_remove(index)
+}
+
+// Files whose metadata can't be read lose on every policy
+fn modified(path: &Path) -> u64 {
+    path.stat().map(|stat| stat.modified).unwrap_or(u64::MAX)
+}
+
+fn extent_count(path: &Path) -> u64 {
+    File::open(path)
+        .and_then(|file| fiemap::extent_count(&file))
+        .map(|count| count as u64)
+        .unwrap_or(u64::MAX)
+}
+
+fn shared_bytes(path: &Path) -> u64 {
+    File::open(path).and_then(|file| fiemap::shared_bytes(&file)).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_source, SourcePolicy};
+    use std::sync::Arc;
+
+    // None of these exist, so they all tie on age
+    fn paths(names: &[&str]) -> Vec<Arc<Path>> {
+        names.iter().map(|name| Arc::new(Path::new(format!("/nonexistent/{}", name)))).collect()
+    }
+
+    #[test]
+    fn test_shortest_path() {
+        let mut group = paths(&["abc/def", "a", "ab"]);
+
+        let source = choose_source(SourcePolicy::Shortest, None, &mut group);
+
+        assert_eq!(Path::new("/nonexistent/a"), *source);
+        assert_eq!(2, group.len());
+    }
+
+    #[test]
+    fn test_reference_dir() {
+        let mut group = paths(&["a/1", "reference/2", "b/3"]);
+        let reference = Path::new("/nonexistent/reference");
+
+        let source = choose_source(SourcePolicy::Reference, Some(&reference), &mut group);
+
+        assert_eq!(Path::new("/nonexistent/reference/2"), *source);
+    }
+
+    #[test]
+    fn test_reference_dir_falls_back_without_a_match() {
+        let mut group = paths(&["b", "a", "c"]);
+        let reference = Path::new("/elsewhere");
+
+        let source = choose_source(SourcePolicy::Reference, Some(&reference), &mut group);
+
+        assert_eq!(Path::new("/nonexistent/a"), *source);
+    }
+
+    #[test]
+    fn test_ties_are_broken_the_same_way_whatever_the_order() {
+        let mut group1 = paths(&["c", "a", "b"]);
+        let mut group2 = paths(&["b", "c", "a"]);
+
+        let source1 = choose_source(SourcePolicy::Oldest, None, &mut group1);
+        let source2 = choose_source(SourcePolicy::Oldest, None, &mut group2);
+
+        assert_eq!(source1, source2);
+        assert_eq!(Path::new("/nonexistent/a"), *source1);
+    }
+}
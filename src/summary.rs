@@ -0,0 +1,91 @@
+use filehasher::HashError;
+use signals;
+
+use std::sync::Arc;
+
+// On dry runs, the bytes are estimates of what would have been deduped or punched
+pub struct Summary {
+    pub dry_run:       bool,
+    pub deduped_bytes: usize,
+    pub groups:        usize,
+    pub already_deduped: usize,
+    pub punched_bytes: u64,
+    pub failures:      Vec<(Arc<Path>, HashError)>,
+    pub skipped:       Vec<(Path, String)>,
+}
+
+pub fn new(dry_run: bool) -> Summary {
+    Summary {
+        dry_run:       dry_run,
+        deduped_bytes: 0,
+        groups:        0,
+        already_deduped: 0,
+        punched_bytes: 0,
+        failures:      Vec::new(),
+        skipped:       Vec::new(),
+    }
+}
+
+impl Summary {
+    pub fn add_dedup(&mut self, deduped_bytes: usize) {
+        self.deduped_bytes += deduped_bytes;
+        self.groups += 1;
+    }
+
+    // `files` were found to share all their data with another file already
+    pub fn add_already_deduped(&mut self, files: usize) {
+        self.already_deduped += files;
+    }
+
+    pub fn add_punched(&mut self, punched_bytes: u64) {
+        self.punched_bytes += punched_bytes;
+    }
+
+    pub fn add_failure(&mut self, path: Arc<Path>, error: HashError) {
+        self.failures.push((path, error));
+    }
+
+    // Files left alone when applying a plan, as they changed since it was made
+    pub fn add_skipped(&mut self, path: Path, reason: String) {
+        self.skipped.push((path, reason));
+    }
+
+    pub fn print(&self) {
+        if signals::cancelled() {
+            println!("Interrupted, some files weren't looked at");
+        }
+
+        if self.dry_run {
+            println!("Would dedup about {} bytes on {} groups (dry run, nothing was changed)",
+                     self.deduped_bytes, self.groups);
+        } else {
+            println!("Deduped {} bytes on {} groups", self.deduped_bytes, self.groups);
+        }
+
+        if self.punched_bytes > 0 {
+            let verb = if self.dry_run { "Would punch" } else { "Punched" };
+            println!("{} {} bytes of zeros into holes", verb, self.punched_bytes);
+        }
+
+        if self.already_deduped > 0 {
+            println!("{} files were already deduped", self.already_deduped);
+        }
+
+        if !self.skipped.is_empty() {
+            println!("\n{} files changed since the plan was made and were skipped:",
+                     self.skipped.len());
+
+            for &(ref path, ref reason) in self.skipped.iter() {
+                println!("- {}: {}", path.display(), reason);
+            }
+        }
+
+        if self.failures.is_empty() { return; }
+
+        println!("\n{} files couldn't be read:", self.failures.len());
+
+        for &(ref path, ref failure) in self.failures.iter() {
+            println!("- {}: {} (errno {})", path.display(), failure.error, failure.errno);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::old_io::fs::PathExtensions;
+use std::old_io::{File, IoResult};
+use std::old_io::timer;
+use std::num::Float;
+use std::thread::Thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+
+use time;
+
+const NANOS_PER_SEC: f64 = 1_000_000_000.0;
+
+// Token bucket shared by every thread doing one kind of IO. Callers are let
+// through right away and go into debt, then wait for the debt to be repaid, so
+// a single big request doesn't need the bucket to be as big as itself.
+pub struct Throttle {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // bytes per second. `None` means unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: Option<u64>) -> Throttle {
+        let bucket = Bucket {
+            rate: rate,
+            tokens: 0.0,
+            last_refill: time::precise_time_ns(),
+        };
+
+        Throttle { bucket: Mutex::new(bucket) }
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        if bucket.rate != rate {
+            info!("Changing rate limit from {:?} to {:?} bytes/s", bucket.rate, rate);
+
+            bucket.rate   = rate;
+            bucket.tokens = 0.0;
+            bucket.last_refill = time::precise_time_ns();
+        }
+    }
+
+    // Blocks until `bytes` can be transferred without going over the rate
+    pub fn acquire(&self, bytes: usize) {
+        let wait_ns = self.bucket.lock().unwrap().take(bytes, time::precise_time_ns());
+
+        if wait_ns > 0 {
+            timer::sleep(Duration::nanoseconds(wait_ns as i64));
+        }
+    }
+}
+
+impl Bucket {
+    // Takes `bytes` worth of tokens at `now`, returning how long to wait (in
+    // nanoseconds) for the debt that leaves, if any
+    fn take(&mut self, bytes: usize, now: u64) -> u64 {
+        let rate = match self.rate {
+            Some(rate) => rate as f64,
+            None       => return 0,
+        };
+
+        let elapsed = (now - self.last_refill) as f64 / NANOS_PER_SEC;
+
+        // At most one second worth of burst
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            0
+        } else {
+            (-self.tokens / rate * NANOS_PER_SEC) as u64
+        }
+    }
+}
+
+// Watches a file with the read and dedup limits, applying any changes to it
+// while running. The file has one `read=<bytes/s>` and/or one
+// `dedup=<bytes/s>` line. 0 means unlimited; a missing line leaves the limit as is.
+pub fn watch_control_file(path: Path, read: Arc<Throttle>, dedup: Arc<Throttle>) {
+    Thread::spawn(move || {
+        let mut last_modified = None;
+
+        loop {
+            let modified = path.stat().ok().map(|stat| stat.modified);
+
+            if modified.is_some() && modified != last_modified {
+                last_modified = modified;
+
+                match read_control_file(&path) {
+                    Ok((read_rate, dedup_rate)) => {
+                        if let Some(rate) = read_rate  { read.set_rate(rate); }
+                        if let Some(rate) = dedup_rate { dedup.set_rate(rate); }
+                    },
+
+                    Err(err) => warn!("Couldn't read throttle file {}: {}", path.display(), err),
+                }
+            }
+
+            timer::sleep(Duration::seconds(1));
+        }
+    });
+}
+
+fn read_control_file(path: &Path) -> IoResult<(Option<Option<u64>>, Option<Option<u64>>)> {
+    let contents = try!(File::open(path).read_to_string());
+    Ok(parse_control_file(&contents[]))
+}
+
+// The new read and dedup rates, each `None` when it isn't set at all
+fn parse_control_file(contents: &str) -> (Option<Option<u64>>, Option<Option<u64>>) {
+    let mut read  = None;
+    let mut dedup = None;
+
+    for line in contents.lines() {
+        let mut parts = line.splitn(1, '=');
+
+        let key   = parts.next().unwrap_or("").trim();
+        let value = parts.next().and_then(|value| value.trim().parse::<u64>().ok());
+
+        let rate = match value {
+            Some(0)    => None,
+            Some(rate) => Some(rate),
+            None       => {
+                if !key.is_empty() { warn!("Ignoring throttle file line: {}", line); }
+                continue;
+            }
+        };
+
+        match key {
+            "read"  => read  = Some(rate),
+            "dedup" => dedup = Some(rate),
+            _       => warn!("Ignoring throttle file line: {}", line),
+        }
+    }
+
+    (read, dedup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_control_file, Bucket};
+
+    fn bucket(rate: Option<u64>) -> Bucket {
+        Bucket { rate: rate, tokens: 0.0, last_refill: 0 }
+    }
+
+    #[test]
+    fn test_parses_rates() {
+        assert_eq!((Some(Some(100)), Some(Some(200))), parse_control_file("read=100\ndedup = 200\n"));
+    }
+
+    #[test]
+    fn test_zero_means_unlimited() {
+        assert_eq!((Some(None), None), parse_control_file("read=0\n"));
+    }
+
+    #[test]
+    fn test_missing_keys_leave_limits_alone() {
+        assert_eq!((None, Some(Some(5))), parse_control_file("dedup=5"));
+        assert_eq!((None, None), parse_control_file(""));
+    }
+
+    #[test]
+    fn test_ignores_bad_lines() {
+        let contents = "read=fast\nwrite=10\n\njunk\ndedup=7\n";
+        assert_eq!((None, Some(Some(7))), parse_control_file(contents));
+    }
+
+    #[test]
+    fn test_unlimited_never_waits() {
+        assert_eq!(0, bucket(None).take(1 << 30, 0));
+    }
+
+    #[test]
+    fn test_waits_for_the_debt() {
+        let mut bucket = bucket(Some(1000));
+
+        // Empty bucket: 500 bytes at 1000 bytes/s is half a second of debt
+        assert_eq!(500_000_000, bucket.take(500, 0));
+
+        // Half a second later the debt is repaid, and another half second covers
+        // what's taken next
+        assert_eq!(0, bucket.take(0, 500_000_000));
+        assert_eq!(0, bucket.take(500, 1_000_000_000));
+    }
+
+    #[test]
+    fn test_bursts_are_capped_at_one_second() {
+        let mut bucket = bucket(Some(1000));
+
+        // Ten idle seconds only fill the bucket up to the rate
+        assert_eq!(0, bucket.take(1000, 10_000_000_000));
+        assert_eq!(1_000_000_000, bucket.take(1000, 10_000_000_000));
+    }
+}
@@ -0,0 +1,308 @@
+// A minimal io_uring (Linux 5.1+) binding: just enough to keep many reads in
+// flight from a single thread.
+
+use libc::{self, c_int, c_long, c_uint, c_void, size_t};
+
+use std::intrinsics::{atomic_load_acq, atomic_store_rel};
+use std::old_io::{IoError, IoResult};
+use std::{cmp, mem, ptr};
+
+const SYS_IO_URING_SETUP: c_long = 425;
+const SYS_IO_URING_ENTER: c_long = 426;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES:    i64 = 0x10000000;
+
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+const IORING_ENTER_GETEVENTS:  c_uint = 1 << 0;
+
+const IORING_OP_READV: u8 = 1;
+
+const EINTR: c_int = 4;
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+}
+
+#[repr(C)]
+struct io_sqring_offsets {
+    head:         u32,
+    tail:         u32,
+    ring_mask:    u32,
+    ring_entries: u32,
+    flags:        u32,
+    dropped:      u32,
+    array:        u32,
+    resv1:        u32,
+    user_addr:    u64,
+}
+
+#[repr(C)]
+struct io_cqring_offsets {
+    head:         u32,
+    tail:         u32,
+    ring_mask:    u32,
+    ring_entries: u32,
+    overflow:     u32,
+    cqes:         u32,
+    flags:        u32,
+    resv1:        u32,
+    user_addr:    u64,
+}
+
+#[repr(C)]
+struct io_uring_params {
+    sq_entries:     u32,
+    cq_entries:     u32,
+    flags:          u32,
+    sq_thread_cpu:  u32,
+    sq_thread_idle: u32,
+    features:       u32,
+    wq_fd:          u32,
+    resv:           [u32; 3],
+    sq_off:         io_sqring_offsets,
+    cq_off:         io_cqring_offsets,
+}
+
+#[repr(C)]
+struct io_uring_sqe {
+    opcode:       u8,
+    flags:        u8,
+    ioprio:       u16,
+    fd:           i32,
+    off:          u64,
+    addr:         u64,
+    len:          u32,
+    rw_flags:     u32,
+    user_data:    u64,
+    buf_index:    u16,
+    personality:  u16,
+    splice_fd_in: i32,
+    pad2:         [u64; 2],
+}
+
+#[repr(C)]
+struct io_uring_cqe {
+    user_data: u64,
+    res:       i32,
+    flags:     u32,
+}
+
+#[repr(C)]
+pub struct IoVec {
+    pub base: *mut c_void,
+    pub len:  size_t,
+}
+
+pub struct Ring {
+    fd: c_int,
+
+    sq_mapping: *mut c_void,
+    sq_mapping_size: usize,
+    cq_mapping: *mut c_void,
+    cq_mapping_size: usize,
+    sqes_mapping_size: usize,
+
+    sq_head:    *const u32,
+    sq_tail:    *mut u32,
+    sq_mask:    u32,
+    sq_entries: u32,
+    sq_array:   *mut u32,
+    sqes:       *mut io_uring_sqe,
+
+    cq_head: *mut u32,
+    cq_tail: *const u32,
+    cq_mask: u32,
+    cqes:    *const io_uring_cqe,
+
+    to_submit: u32,
+}
+
+impl Ring {
+    pub fn new(entries: u32) -> IoResult<Ring> {
+        let mut params: io_uring_params = unsafe { mem::zeroed() };
+
+        let fd = unsafe {
+            syscall(SYS_IO_URING_SETUP, entries as c_long, &mut params as *mut io_uring_params)
+        } as c_int;
+
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+
+        let mut ring = Ring {
+            fd: fd,
+            sq_mapping: ptr::null_mut(),
+            sq_mapping_size: 0,
+            cq_mapping: ptr::null_mut(),
+            cq_mapping_size: 0,
+            sqes_mapping_size: params.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+            sq_head: ptr::null(),
+            sq_tail: ptr::null_mut(),
+            sq_mask: 0,
+            sq_entries: params.sq_entries,
+            sq_array: ptr::null_mut(),
+            sqes: ptr::null_mut(),
+            cq_head: ptr::null_mut(),
+            cq_tail: ptr::null(),
+            cq_mask: 0,
+            cqes: ptr::null(),
+            to_submit: 0,
+        };
+
+        let sq_size = params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_size = params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+
+        // Since 5.4 both rings can share a single mapping
+        if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            let size = cmp::max(sq_size, cq_size);
+
+            ring.sq_mapping      = try!(map(fd, size, IORING_OFF_SQ_RING));
+            ring.sq_mapping_size = size;
+        } else {
+            ring.sq_mapping      = try!(map(fd, sq_size, IORING_OFF_SQ_RING));
+            ring.sq_mapping_size = sq_size;
+            ring.cq_mapping      = try!(map(fd, cq_size, IORING_OFF_CQ_RING));
+            ring.cq_mapping_size = cq_size;
+        }
+
+        let sqes = try!(map(fd, ring.sqes_mapping_size, IORING_OFF_SQES));
+        ring.sqes = sqes as *mut io_uring_sqe;
+
+        unsafe {
+            let sq = ring.sq_mapping as *mut u8;
+            let cq = if ring.cq_mapping.is_null() { sq } else { ring.cq_mapping as *mut u8 };
+
+            ring.sq_head  = sq.offset(params.sq_off.head as isize) as *const u32;
+            ring.sq_tail  = sq.offset(params.sq_off.tail as isize) as *mut u32;
+            ring.sq_mask  = *(sq.offset(params.sq_off.ring_mask as isize) as *const u32);
+            ring.sq_array = sq.offset(params.sq_off.array as isize) as *mut u32;
+
+            ring.cq_head = cq.offset(params.cq_off.head as isize) as *mut u32;
+            ring.cq_tail = cq.offset(params.cq_off.tail as isize) as *const u32;
+            ring.cq_mask = *(cq.offset(params.cq_off.ring_mask as isize) as *const u32);
+            ring.cqes    = cq.offset(params.cq_off.cqes as isize) as *const io_uring_cqe;
+        }
+
+        Ok(ring)
+    }
+
+    // Queues a read of `iovec` from `fd` at `offset`. `iovec` (and its buffer)
+    // must stay put until the read completes. Returns false if the submission
+    // queue is full.
+    pub fn push_readv(&mut self, fd: c_int, iovec: *const IoVec, offset: u64, user_data: u64) -> bool {
+        unsafe {
+            let head = atomic_load_acq(self.sq_head);
+            let tail = *self.sq_tail;
+
+            if tail.wrapping_sub(head) >= self.sq_entries {
+                return false;
+            }
+
+            let index = tail & self.sq_mask;
+            let mut sqe: io_uring_sqe = mem::zeroed();
+
+            sqe.opcode    = IORING_OP_READV;
+            sqe.fd        = fd;
+            sqe.off       = offset;
+            sqe.addr      = iovec as u64;
+            sqe.len       = 1;
+            sqe.user_data = user_data;
+
+            ptr::write(self.sqes.offset(index as isize), sqe);
+            *self.sq_array.offset(index as isize) = index;
+
+            atomic_store_rel(self.sq_tail, tail.wrapping_add(1));
+        }
+
+        self.to_submit += 1;
+        true
+    }
+
+    // Submits all queued reads, and waits until at least `wait_for` have completed.
+    // Fails with the errno of the call.
+    pub fn submit_and_wait(&mut self, wait_for: u32) -> Result<(), i32> {
+        let submitted = unsafe {
+            syscall(
+                SYS_IO_URING_ENTER,
+                self.fd as c_long,
+                self.to_submit as c_long,
+                wait_for as c_long,
+                IORING_ENTER_GETEVENTS as c_long,
+                ptr::null::<c_void>(),
+                0 as c_long
+            )
+        };
+
+        if submitted < 0 {
+            let errno = ::std::os::errno() as c_int;
+
+            return if errno == EINTR { Ok(()) } else { Err(errno) };
+        }
+
+        self.to_submit -= submitted as u32;
+        Ok(())
+    }
+
+    // Reaps all available completions, as (user_data, result) pairs. A negative
+    // result is a negated errno.
+    pub fn completions(&mut self) -> Vec<(u64, i32)> {
+        let mut completions = Vec::new();
+
+        unsafe {
+            let mut head = *self.cq_head;
+            let tail = atomic_load_acq(self.cq_tail);
+
+            while head != tail {
+                let cqe = &*self.cqes.offset((head & self.cq_mask) as isize);
+                completions.push((cqe.user_data, cqe.res));
+
+                head = head.wrapping_add(1);
+            }
+
+            atomic_store_rel(self.cq_head, head);
+        }
+
+        completions
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.sqes.is_null() {
+                libc::munmap(self.sqes as *mut c_void, self.sqes_mapping_size as size_t);
+            }
+
+            if !self.cq_mapping.is_null() {
+                libc::munmap(self.cq_mapping, self.cq_mapping_size as size_t);
+            }
+
+            if !self.sq_mapping.is_null() {
+                libc::munmap(self.sq_mapping, self.sq_mapping_size as size_t);
+            }
+
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn map(fd: c_int, size: usize, offset: i64) -> IoResult<*mut c_void> {
+    let mapping = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size as size_t,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            offset as libc::off_t
+        )
+    };
+
+    if mapping == libc::MAP_FAILED {
+        Err(IoError::last_error())
+    } else {
+        Ok(mapping)
+    }
+}